@@ -3,11 +3,21 @@ export async function get_raw(url) {
   return new Uint8Array(await response.arrayBuffer());
 }
 
+export async function head_status(url) {
+  const response = await fetch(url, {
+    "method": "HEAD",
+  });
+  return response.status;
+}
+
 export async function post_raw(url, body) {
   const response = await fetch(url, {
     "method": "POST",
     "body": body,
   });
+  if (!response.ok) {
+    throw new Error(`${response.status}: ${await response.text()}`);
+  }
   return new Uint8Array(await response.arrayBuffer());
 }
 
@@ -17,6 +27,9 @@ export function save_raw(key, value) {
 
 export function load_raw(key) {
   let map = JSON.parse(localStorage.getItem(key))
+  if (map === null) {
+    return new Uint8Array();
+  }
   return new Uint8Array(Object.keys(map).map((i) => map[i]))
 }
 
@@ -2,7 +2,10 @@ pub mod servie_conn;
 pub mod ws;
 
 use crate::servie_conn::ServieConn;
-use schemou::{legos::ShortIdStr, C2RRegister, R2CRegister, Sirius};
+use schemou::{
+    legos::{MlDsaPublicKey, MlDsaSignature, ShortIdStr},
+    C2RRegister, DeviceId, R2CRegister, Sirius, REGISTER_CONTEXT,
+};
 
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::js_sys::Uint8Array;
@@ -15,6 +18,9 @@ extern "C" {
     #[wasm_bindgen(catch)]
     async fn post_raw(url: &str, body: &[u8]) -> Result<Uint8Array, JsValue>;
 
+    #[wasm_bindgen(catch)]
+    async fn head_status(url: &str) -> Result<JsValue, JsValue>;
+
     fn save_raw(key: &str, value: &[u8]);
 
     fn load_raw(key: &str) -> Box<[u8]>;
@@ -29,13 +35,30 @@ extern "C" {
     fn log(msg: &str);
 }
 
+const REGISTRIE_URL: &str = "http://localhost:8081";
+
+/// Returns `true` if the username is available for registration
+#[wasm_bindgen(js_name = "checkUsername")]
+pub async fn check_username(username: &str) -> Result<bool, JsValue> {
+    let username = ShortIdStr::new(username)
+        .map_err(|e| JsValue::from_str(&format!("Invalid username: {e}")))?;
+
+    let status = head_status(&format!("{REGISTRIE_URL}/record/{}", *username))
+        .await?
+        .as_f64()
+        .ok_or_else(|| JsValue::from_str("Invalid Response: non-numeric status"))?;
+
+    match status as u16 {
+        200 => Ok(false),
+        404 => Ok(true),
+        status => Err(JsValue::from_str(&format!(
+            "Invalid Response: unexpected status {status}"
+        ))),
+    }
+}
+
 #[wasm_bindgen]
 pub async fn register(username: &str) -> Result<(), JsValue> {
-    // TODO: Check if the username is already registered
-    // This is not trivial, needs discussion if we could hit registrie for read calls
-    // labels: help wanted, discussion
-    // Issue URL: https://github.com/Colabie/Colabie/issues/6
-
     let username = ShortIdStr::new(username)
         .map_err(|e| JsValue::from_str(&format!("Invalid username: {e}")))?;
 
@@ -47,14 +70,21 @@ pub async fn register(username: &str) -> Result<(), JsValue> {
     save_raw("sk_key", &sk_key);
     save_raw("username", username.as_bytes());
 
+    let signature = sign(
+        &sk_key,
+        &C2RRegister::signed_payload(&username, &pb_key),
+        REGISTER_CONTEXT,
+    )?;
+
     let register = C2RRegister {
         username,
         pubkey: pb_key,
+        signature,
     };
 
     let (resp, _) = R2CRegister::deserialize(
         &post_raw(
-            "http://localhost:8081/register",
+            &format!("{REGISTRIE_URL}/register"),
             &register.serialize_buffered(),
         )
         .await?
@@ -62,13 +92,17 @@ pub async fn register(username: &str) -> Result<(), JsValue> {
     )
     .map_err(|e| JsValue::from_str(&format!("Invalid Response: {e}")))?;
 
+    // Servie uses this as a hint that its registrie mirror might be stale
+    save_raw("commit_id", &resp.commit_id);
+
     alert(&format!("Registered: {:#?}", resp.commit_id));
 
     Ok(())
 }
 
+/// `hide_presence` makes the user appear offline to presence subscribers
 #[wasm_bindgen]
-pub async fn login() -> Result<ServieConn, JsValue> {
+pub async fn login(hide_presence: bool) -> Result<ServieConn, JsValue> {
     let username = load_raw("username");
     let username = str::from_utf8(&username)
         .map_err(|e| JsValue::from_str(&format!("Unreachable: Corrupted username {e}")))?;
@@ -78,18 +112,61 @@ pub async fn login() -> Result<ServieConn, JsValue> {
     // Issue URL: https://github.com/Colabie/Colabie/issues/5
     let sk_key = load_raw("sk_key");
 
-    ServieConn::new("ws://localhost:8082/connect", username, &sk_key).await
+    let commit_id = load_raw("commit_id");
+
+    ServieConn::new(
+        "ws://localhost:8082/connect",
+        username,
+        device_id(),
+        &sk_key,
+        &commit_id,
+        hide_presence,
+    )
+    .await
+}
+
+/// Id of this browser as a device of the user, generated on the first login
+fn device_id() -> DeviceId {
+    use rand_chacha::rand_core::{RngCore, SeedableRng};
+
+    if let Ok(device) = <[u8; 8]>::try_from(&*load_raw("device_id")) {
+        return DeviceId::from_le_bytes(device);
+    }
+
+    let device = rand_chacha::ChaChaRng::from_entropy().next_u64();
+    save_raw("device_id", &device.to_le_bytes());
+    device
 }
 
 // TODO: Use more robust hybrid cryptographic methods instead
 // labels: enhancement
 // Issue URL: https://github.com/Colabie/Colabie/issues/4
-fn generate_keypair() -> (Box<[u8]>, Box<[u8]>) {
+fn generate_keypair() -> (MlDsaPublicKey, Box<[u8]>) {
     use fips204::ml_dsa_87;
     use fips204::traits::SerDes;
     use rand_chacha::rand_core::SeedableRng;
 
     let mut rng = rand_chacha::ChaChaRng::from_entropy();
     let (pb_key, sk_key) = ml_dsa_87::try_keygen_with_rng(&mut rng).unwrap();
-    (pb_key.into_bytes().into(), sk_key.into_bytes().into())
+    (
+        MlDsaPublicKey::new(pb_key.into_bytes()).expect("Unreachable: ML-DSA-87 public key"),
+        sk_key.into_bytes().into(),
+    )
+}
+
+fn sign(sk_key: &[u8], message: &[u8], ctx: &[u8]) -> Result<MlDsaSignature, JsValue> {
+    use fips204::ml_dsa_87;
+    use fips204::traits::{SerDes, Signer};
+    use rand_chacha::rand_core::SeedableRng;
+
+    let sk_key = sk_key
+        .try_into()
+        .map_err(|_| JsValue::from_str("Corrupted secret key"))?;
+    let sk_key = ml_dsa_87::PrivateKey::try_from_bytes(sk_key).map_err(JsValue::from_str)?;
+
+    let mut rng = rand_chacha::ChaChaRng::from_entropy();
+    let signature = sk_key
+        .try_sign_with_rng(&mut rng, message, ctx)
+        .map_err(JsValue::from_str)?;
+    Ok(MlDsaSignature::new(signature).expect("Unreachable: ML-DSA-87 signature"))
 }
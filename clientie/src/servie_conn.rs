@@ -1,111 +1,827 @@
-use crate::{alert, confirm, log, ws::WebSocket};
+use crate::{alert, confirm, log, sign, ws::WebSocket};
 use schemou::{
-    legos::ShortIdStr, C2SAck, C2SAuthRes, C2SConnectToUserResult, ConnectToUser, S2CAuthReq,
-    S2CAuthResult, S2CConnectToUserResult,
+    legos::{BoundedBytes, BoundedVec, CommitId, ShortIdStr},
+    C2SAck, C2SAuthRes, C2SCancelConnect, C2SConnectToUserReply, C2SConnectToUserResult,
+    C2SContactRequest, C2SGetBlockList, C2SGetContacts, C2SHello, C2SMailAck, C2SRelayFrame,
+    C2SResume, C2SSendMail, C2SSetBlocked, C2SSetContact, C2SSetContactsOnly, C2SSetPresence,
+    C2SSignal, C2SSubscribePresence, ConnectToUser, DeviceId, MailEnvelope, RequestId, ResumeToken,
+    S2CAuthReq, S2CAuthResult, S2CBlockList, S2CConnectCancelled, S2CConnectHandledElsewhere,
+    S2CConnectToUserReply, S2CConnectToUserResult, S2CContactRequest, S2CContactRequestReply,
+    S2CContactRequestResult, S2CContacts, S2CHello, S2CMail, S2CPresence, S2CRelayFrame,
+    S2CResumeResult, S2CResumeToken, S2CSendMailReply, S2CSendMailResult, S2CSetBlockedReply,
+    S2CSetBlockedResult, S2CSetContactReply, S2CSetContactResult, S2CSignal, Signal, AUTH_CONTEXT,
+    C2S, MAX_PRESENCE_SUBSCRIPTIONS, PROTOCOL_VERSION, S2C,
 };
 
-use futures::{channel::mpsc, select, FutureExt, SinkExt, StreamExt};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    select, FutureExt, SinkExt, StreamExt,
+};
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::spawn_local;
+use wasm_bindgen_futures::{js_sys, spawn_local};
 
 pub enum ClientEvent {
-    ConnectToUser(ConnectToUser),
+    /// Reply is sent back once servie answers the request
+    ConnectToUser {
+        username: ShortIdStr,
+        reply: oneshot::Sender<S2CConnectToUserResult>,
+    },
+    /// Withdraws the pending request to `username`, its reply resolves as `Cancelled`
+    CancelConnect {
+        username: ShortIdStr,
+    },
+    Signal(C2SSignal),
+    RelayFrame(C2SRelayFrame),
+    /// Reply is sent back once servie stored the mail, or refused to
+    SendMail {
+        to: ShortIdStr,
+        envelope: MailEnvelope,
+        reply: oneshot::Sender<S2CSendMailResult>,
+    },
+    SubscribePresence(C2SSubscribePresence),
+    SetPresence(C2SSetPresence),
+    /// Reply is sent back once servie updated the block list, or refused to
+    SetBlocked {
+        username: ShortIdStr,
+        blocked: bool,
+        reply: oneshot::Sender<S2CSetBlockedResult>,
+    },
+    GetBlockList {
+        reply: oneshot::Sender<Vec<ShortIdStr>>,
+    },
+    SetContactsOnly(C2SSetContactsOnly),
+    /// Reply is sent back once servie updated the contacts, or refused to
+    SetContact {
+        username: ShortIdStr,
+        contact: bool,
+        reply: oneshot::Sender<S2CSetContactResult>,
+    },
+    GetContacts {
+        reply: oneshot::Sender<S2CContacts>,
+    },
+    /// Reply is sent back once servie delivered the request, or refused to
+    ContactRequest {
+        username: ShortIdStr,
+        reply: oneshot::Sender<S2CContactRequestResult>,
+    },
 }
 
 #[wasm_bindgen]
 pub struct ServieConn {
     tx: mpsc::Sender<ClientEvent>,
+    /// Called with `(from, kind, payload)` for every WebRTC signal from a peer
+    on_signal: Rc<RefCell<Option<js_sys::Function>>>,
+    /// Called with `(from, frame)` for every frame relayed by servie
+    on_relay_frame: Rc<RefCell<Option<js_sys::Function>>>,
+    /// Called with `(from, sent_at, envelope)` for every mail, which is acknowledged
+    /// only if the handler returns without throwing
+    on_mail: Rc<RefCell<Option<js_sys::Function>>>,
+    /// Called with `(username, online)` for every presence update of a subscribed user
+    on_presence: Rc<RefCell<Option<js_sys::Function>>>,
+    /// Called with `(username)` for every contact request
+    on_contact_request: Rc<RefCell<Option<js_sys::Function>>>,
+}
+
+/// Connects to servie and agrees on the protocol version
+async fn hello(url: &str) -> Result<WebSocket, JsValue> {
+    let mut ws = WebSocket::new(url).await?;
+    ws.send_se(C2S::Hello(C2SHello {
+        version: PROTOCOL_VERSION,
+    }))?;
+
+    let S2C::Hello(S2CHello { version }) = ws.recv_s2c().await? else {
+        return Err(JsValue::from_str("Expected S2CHello"));
+    };
+    if version != PROTOCOL_VERSION {
+        return Err(JsValue::from_str(&format!(
+            "Incompatible servie protocol version {version}, expected {PROTOCOL_VERSION}"
+        )));
+    }
+
+    Ok(ws)
+}
+
+/// Reconnects to servie and takes back the session it keeps for `token`
+async fn resume(
+    url: &str,
+    username: &ShortIdStr,
+    device: DeviceId,
+    token: ResumeToken,
+) -> Result<WebSocket, JsValue> {
+    let mut ws = hello(url).await?;
+    ws.send_se(C2S::from(C2SResume {
+        username: username.clone(),
+        device,
+        token,
+    }))?;
+
+    match ws.recv_s2c().await? {
+        S2C::ResumeResult(S2CResumeResult::Success) => Ok(ws),
+        _ => Err(JsValue::from_str("Servie no longer has the session")),
+    }
 }
 
 #[wasm_bindgen]
 impl ServieConn {
     #[wasm_bindgen(constructor)]
-    pub async fn new(url: &str, username: &str, _sk_key: &[u8]) -> Result<ServieConn, JsValue> {
+    /// `device` tells apart the user's sessions on different devices, it should stay
+    /// the same across logins from one device.
+    /// `commit_hint` is the last known registrie commit id, empty if unknown,
+    /// `hide_presence` makes the user appear offline until `setPresenceHidden(false)`
+    pub async fn new(
+        url: &str,
+        username: &str,
+        device: DeviceId,
+        sk_key: &[u8],
+        commit_hint: &[u8],
+        hide_presence: bool,
+    ) -> Result<ServieConn, JsValue> {
         let username = ShortIdStr::new(username)
             .map_err(|e| JsValue::from_str(&format!("Invalid username: {e}")))?;
 
-        let mut ws = WebSocket::new(url).await?;
-        ws.send_se(C2SAck { username })?;
+        let mut ws = hello(url).await?;
+        ws.send_se(C2S::Ack(C2SAck {
+            username: username.clone(),
+            device,
+            commit_hint: CommitId::new(commit_hint).ok(),
+            hide_presence,
+        }))?;
 
-        let S2CAuthReq { random } = ws.recv_de().await?;
-        // TODO: Verify the User and signed random
-        // labels: enhancement
-        // Issue URL: https://github.com/Colabie/Colabie/issues/54
-        // coupled with #4
-        ws.send_se(C2SAuthRes {
-            signed_random: random,
-        })?;
+        let S2C::AuthReq(S2CAuthReq { random }) = ws.recv_s2c().await? else {
+            return Err(JsValue::from_str("Expected S2CAuthReq"));
+        };
+        ws.send_se(C2S::AuthRes(C2SAuthRes {
+            signature: sign(sk_key, &random, AUTH_CONTEXT)?,
+        }))?;
 
-        let auth_result = ws.recv_de().await?;
-        let S2CAuthResult::Success = auth_result else {
+        let S2C::AuthResult(S2CAuthResult::Success) = ws.recv_s2c().await? else {
             return Err(JsValue::from_str("Authentication failed"));
         };
 
         let (tx, mut rx) = mpsc::channel(1);
+        let on_signal: Rc<RefCell<Option<js_sys::Function>>> = Default::default();
+
+        let on_relay_frame: Rc<RefCell<Option<js_sys::Function>>> = Default::default();
 
+        let on_mail: Rc<RefCell<Option<js_sys::Function>>> = Default::default();
+
+        let on_presence: Rc<RefCell<Option<js_sys::Function>>> = Default::default();
+
+        let on_contact_request: Rc<RefCell<Option<js_sys::Function>>> = Default::default();
+
+        let signal_handler = on_signal.clone();
+        let relay_frame_handler = on_relay_frame.clone();
+        let mail_handler = on_mail.clone();
+        let presence_handler = on_presence.clone();
+        let contact_request_handler = on_contact_request.clone();
+        let url = url.to_string();
         spawn_local(async move {
-            async {
-                loop {
-                    select! {
-                        server_msg = ws.recv_de::<ConnectToUser>().fuse() => {
-                            let server_msg = server_msg?;
-                            let connect = confirm(&format!("User {} wants to connect to you", *server_msg.username));
-                            if connect {
-                                ws.send_se(C2SConnectToUserResult::Accept)?;
-                            } else {
-                                ws.send_se(C2SConnectToUserResult::Reject)?;
-                            }
-                        }
+            // Our requests awaiting a reply from servie, by id
+            let mut pending: HashMap<
+                RequestId,
+                (ShortIdStr, oneshot::Sender<S2CConnectToUserResult>),
+            > = HashMap::new();
+            // Our mails awaiting servie's answer, by id
+            let mut pending_mail: HashMap<RequestId, oneshot::Sender<S2CSendMailResult>> =
+                HashMap::new();
+            // Our block list changes and queries awaiting servie's answer, by id
+            let mut pending_blocked: HashMap<RequestId, oneshot::Sender<S2CSetBlockedResult>> =
+                HashMap::new();
+            let mut pending_block_list: HashMap<RequestId, oneshot::Sender<Vec<ShortIdStr>>> =
+                HashMap::new();
+            // Our contacts changes, queries and contact requests awaiting servie's answer, by id
+            let mut pending_contact: HashMap<RequestId, oneshot::Sender<S2CSetContactResult>> =
+                HashMap::new();
+            let mut pending_contacts: HashMap<RequestId, oneshot::Sender<S2CContacts>> =
+                HashMap::new();
+            let mut pending_contact_request: HashMap<
+                RequestId,
+                oneshot::Sender<S2CContactRequestResult>,
+            > = HashMap::new();
+            let mut next_id: RequestId = 0;
+            // Single use, servie sends a new one after every login or resumption
+            let mut resume_token = None;
+
+            loop {
+                let lost = async {
+                    loop {
+                        select! {
+                            server_msg = ws.recv_s2c().fuse() => {
+                                match server_msg? {
+                                    S2C::ResumeToken(S2CResumeToken { token, .. }) => {
+                                        resume_token = Some(token);
+                                    }
+
+                                    S2C::ConnectToUser(ConnectToUser { id, username }) => {
+                                        let result = match confirm(&format!("User {} wants to connect to you", *username)) {
+                                            true => C2SConnectToUserResult::Accept,
+                                            false => C2SConnectToUserResult::Reject,
+                                        };
+                                        ws.send_se(C2S::from(C2SConnectToUserReply { id, result }))?;
+                                    }
+
+                                    S2C::ConnectToUserResult(S2CConnectToUserReply { id, result }) => {
+                                        match pending.remove(&id) {
+                                            // The caller might have stopped waiting, that's fine
+                                            Some((_, reply)) => _ = reply.send(result),
+                                            None => log(&format!("Reply to an unknown request id {id}")),
+                                        }
+                                    }
+
+                                    // `confirm` blocks, so the answer might already be on its way,
+                                    // servie ignores answers to withdrawn requests
+                                    S2C::ConnectCancelled(S2CConnectCancelled { username, .. }) => {
+                                        alert(&format!("User {} withdrew their connection request", *username));
+                                    }
 
-                        client_ev = rx.next() => {
-                            let client_ev = client_ev.expect("Client event sender was dropped");
+                                    S2C::ConnectHandledElsewhere(S2CConnectHandledElsewhere { username }) => {
+                                        alert(&format!("Connection request from {} was answered on another device", *username));
+                                    }
+
+                                    S2C::Signal(S2CSignal { from, signal }) => {
+                                        let (kind, payload) = match signal {
+                                            Signal::Offer(sdp) => ("offer", sdp),
+                                            Signal::Answer(sdp) => ("answer", sdp),
+                                            Signal::IceCandidate(candidate) => ("candidate", candidate),
+                                        };
+
+                                        match signal_handler.borrow().as_ref() {
+                                            Some(on_signal) => {
+                                                // A throwing handler shouldn't take the connection down
+                                                if let Err(e) = on_signal.call3(
+                                                    &JsValue::NULL,
+                                                    &JsValue::from_str(&from),
+                                                    &JsValue::from_str(kind),
+                                                    &JsValue::from_str(&String::from_utf8_lossy(&payload)),
+                                                ) {
+                                                    log(&format!("Signal handler failed: {e:?}"));
+                                                }
+                                            }
+                                            None => log(&format!("Dropped {kind} from {}, no signal handler", *from)),
+                                        }
+                                    }
+
+                                    S2C::RelayFrame(S2CRelayFrame { from, frame }) => {
+                                        match relay_frame_handler.borrow().as_ref() {
+                                            Some(on_relay_frame) => {
+                                                // A throwing handler shouldn't take the connection down
+                                                if let Err(e) = on_relay_frame.call2(
+                                                    &JsValue::NULL,
+                                                    &JsValue::from_str(&from),
+                                                    &js_sys::Uint8Array::from(&*frame),
+                                                ) {
+                                                    log(&format!("Relay frame handler failed: {e:?}"));
+                                                }
+                                            }
+                                            None => log(&format!("Dropped a frame from {}, no relay frame handler", *from)),
+                                        }
+                                    }
+
+                                    S2C::SendMailResult(S2CSendMailReply { id, result }) => {
+                                        match pending_mail.remove(&id) {
+                                            Some(reply) => _ = reply.send(result),
+                                            None => log(&format!("Reply to an unknown request id {id}")),
+                                        }
+                                    }
+
+                                    S2C::Mail(S2CMail { id, from, sent_at, envelope }) => {
+                                        // Unacknowledged mail is delivered again on the next login
+                                        let handled = match mail_handler.borrow().as_ref() {
+                                            Some(on_mail) => on_mail
+                                                .call3(
+                                                    &JsValue::NULL,
+                                                    &JsValue::from_str(&from),
+                                                    &JsValue::from_f64(sent_at as f64),
+                                                    &js_sys::Uint8Array::from(&*envelope),
+                                                )
+                                                .inspect_err(|e| log(&format!("Mail handler failed: {e:?}")))
+                                                .is_ok(),
+                                            None => false,
+                                        };
+
+                                        if handled {
+                                            ws.send_se(C2S::from(C2SMailAck { id }))?;
+                                        }
+                                    }
+
+                                    S2C::Presence(S2CPresence { username, online }) => {
+                                        if let Some(on_presence) = presence_handler.borrow().as_ref() {
+                                            if let Err(e) = on_presence.call2(
+                                                &JsValue::NULL,
+                                                &JsValue::from_str(&username),
+                                                &JsValue::from_bool(online),
+                                            ) {
+                                                log(&format!("Presence handler failed: {e:?}"));
+                                            }
+                                        }
+                                    }
+
+                                    S2C::SetBlockedResult(S2CSetBlockedReply { id, result }) => {
+                                        match pending_blocked.remove(&id) {
+                                            Some(reply) => _ = reply.send(result),
+                                            None => log(&format!("Reply to an unknown request id {id}")),
+                                        }
+                                    }
 
-                            match client_ev {
-                                ClientEvent::ConnectToUser(connect) => {
-                                    ws.send_se(connect)?;
+                                    S2C::BlockList(S2CBlockList { id, usernames }) => {
+                                        match pending_block_list.remove(&id) {
+                                            Some(reply) => _ = reply.send(usernames.into_inner()),
+                                            None => log(&format!("Reply to an unknown request id {id}")),
+                                        }
+                                    }
 
-                                    let result = ws.recv_de::<S2CConnectToUserResult>().await?;
-                                    match result {
-                                        S2CConnectToUserResult::Accept => {
-                                            alert("User accepted your connection request");
+                                    S2C::SetContactResult(S2CSetContactReply { id, result }) => {
+                                        match pending_contact.remove(&id) {
+                                            Some(reply) => _ = reply.send(result),
+                                            None => log(&format!("Reply to an unknown request id {id}")),
                                         }
-                                        S2CConnectToUserResult::Reject => {
-                                            alert("User rejected your connection request");
+                                    }
+
+                                    S2C::Contacts(contacts) => {
+                                        match pending_contacts.remove(&contacts.id) {
+                                            Some(reply) => _ = reply.send(contacts),
+                                            None => log(&format!("Reply to an unknown request id {}", contacts.id)),
                                         }
-                                        S2CConnectToUserResult::UserBusy => {
-                                            alert("User is busy");
+                                    }
+
+                                    S2C::ContactRequestResult(S2CContactRequestReply { id, result }) => {
+                                        match pending_contact_request.remove(&id) {
+                                            Some(reply) => _ = reply.send(result),
+                                            None => log(&format!("Reply to an unknown request id {id}")),
                                         }
                                     }
+
+                                    S2C::ContactRequest(S2CContactRequest { username }) => {
+                                        match contact_request_handler.borrow().as_ref() {
+                                            Some(on_contact_request) => {
+                                                // A throwing handler shouldn't take the connection down
+                                                if let Err(e) = on_contact_request.call1(&JsValue::NULL, &JsValue::from_str(&username)) {
+                                                    log(&format!("Contact request handler failed: {e:?}"));
+                                                }
+                                            }
+                                            None => log(&format!("Dropped a contact request from {}, no contact request handler", *username)),
+                                        }
+                                    }
+
+                                    msg => {
+                                        log(&format!("Unexpected message from servie: {msg:?}"));
+                                    }
+                                }
+                            }
+
+                            client_ev = rx.next() => {
+                                let client_ev = client_ev.expect("Client event sender was dropped");
+
+                                match client_ev {
+                                    ClientEvent::ConnectToUser { username, reply } => {
+                                        let id = next_id;
+                                        next_id = next_id.wrapping_add(1);
+
+                                        pending.insert(id, (username.clone(), reply));
+                                        ws.send_se(C2S::from(ConnectToUser { id, username }))?;
+                                    }
+
+                                    ClientEvent::CancelConnect { username } => {
+                                        let id = pending
+                                            .iter()
+                                            .find(|(_, (to, _))| *to == username)
+                                            .map(|(id, _)| *id);
+
+                                        match id {
+                                            Some(id) => ws.send_se(C2S::from(C2SCancelConnect { id }))?,
+                                            None => log(&format!("No pending request to {}", *username)),
+                                        }
+                                    }
+
+                                    ClientEvent::Signal(signal) => {
+                                        ws.send_se(C2S::from(signal))?;
+                                    }
+
+                                    ClientEvent::RelayFrame(frame) => {
+                                        ws.send_se(C2S::from(frame))?;
+                                    }
+
+                                    ClientEvent::SendMail { to, envelope, reply } => {
+                                        let id = next_id;
+                                        next_id = next_id.wrapping_add(1);
+
+                                        pending_mail.insert(id, reply);
+                                        ws.send_se(C2S::from(C2SSendMail { id, to, envelope }))?;
+                                    }
+
+                                    ClientEvent::SubscribePresence(subscribe) => {
+                                        ws.send_se(C2S::from(subscribe))?;
+                                    }
+
+                                    ClientEvent::SetPresence(presence) => {
+                                        ws.send_se(C2S::from(presence))?;
+                                    }
+
+                                    ClientEvent::SetBlocked { username, blocked, reply } => {
+                                        let id = next_id;
+                                        next_id = next_id.wrapping_add(1);
+
+                                        pending_blocked.insert(id, reply);
+                                        ws.send_se(C2S::from(C2SSetBlocked { id, username, blocked }))?;
+                                    }
+
+                                    ClientEvent::GetBlockList { reply } => {
+                                        let id = next_id;
+                                        next_id = next_id.wrapping_add(1);
+
+                                        pending_block_list.insert(id, reply);
+                                        ws.send_se(C2S::from(C2SGetBlockList { id }))?;
+                                    }
+
+                                    ClientEvent::SetContactsOnly(contacts_only) => {
+                                        ws.send_se(C2S::from(contacts_only))?;
+                                    }
+
+                                    ClientEvent::SetContact { username, contact, reply } => {
+                                        let id = next_id;
+                                        next_id = next_id.wrapping_add(1);
+
+                                        pending_contact.insert(id, reply);
+                                        ws.send_se(C2S::from(C2SSetContact { id, username, contact }))?;
+                                    }
+
+                                    ClientEvent::GetContacts { reply } => {
+                                        let id = next_id;
+                                        next_id = next_id.wrapping_add(1);
+
+                                        pending_contacts.insert(id, reply);
+                                        ws.send_se(C2S::from(C2SGetContacts { id }))?;
+                                    }
+
+                                    ClientEvent::ContactRequest { username, reply } => {
+                                        let id = next_id;
+                                        next_id = next_id.wrapping_add(1);
+
+                                        pending_contact_request.insert(id, reply);
+                                        ws.send_se(C2S::from(C2SContactRequest { id, username }))?;
+                                    }
                                 }
                             }
                         }
                     }
+
+                    #[allow(unreachable_code)]
+                    // For type inference
+                    Ok::<(), JsValue>(())
+                }
+                .await;
+
+                if let Err(e) = lost {
+                    log(&format!("Connection to servie lost: {e:?}"));
                 }
 
-                #[allow(unreachable_code)]
-                // For type inference
-                Ok::<(), JsValue>(())
+                // Requests and mail in flight survive if servie still has the session
+                let Some(token) = resume_token.take() else {
+                    break;
+                };
+                match resume(&url, &username, device, token).await {
+                    Ok(resumed) => ws = resumed,
+                    Err(e) => {
+                        log(&format!("Could not resume the session: {e:?}"));
+                        break;
+                    }
+                }
             }
-            .await
-            .expect("Error");
         });
 
         log("abcde");
 
-        Ok(ServieConn { tx })
+        Ok(ServieConn {
+            tx,
+            on_signal,
+            on_relay_frame,
+            on_mail,
+            on_presence,
+            on_contact_request,
+        })
     }
 
+    /// Resolves with servie's answer, one of `Accept`, `Reject`, `UserBusy`, `Timeout`, `Cancelled`,
+    /// `Offline` or `MailboxFull`
+    ///
+    /// Several requests can be outstanding at once
     #[wasm_bindgen(js_name = "connectToUser")]
-    pub async fn connect_to_user(&mut self, username: &str) -> Result<(), JsValue> {
+    pub async fn connect_to_user(&self, username: &str) -> Result<String, JsValue> {
         let username = ShortIdStr::new(username)
             .map_err(|e| JsValue::from_str(&format!("Invalid username: {e}")))?;
 
+        let (reply, result) = oneshot::channel();
         self.tx
-            .send(ClientEvent::ConnectToUser(ConnectToUser { username }))
+            .clone()
+            .send(ClientEvent::ConnectToUser { username, reply })
+            .await
+            .expect("Unreachable: Client event receiver was dropped");
+
+        let result = result
+            .await
+            .map_err(|_| JsValue::from_str("Connection to servie closed"))?;
+        Ok(format!("{result:?}"))
+    }
+
+    /// Withdraws the pending `connectToUser` request to `username`
+    #[wasm_bindgen(js_name = "cancelConnect")]
+    pub async fn cancel_connect(&self, username: &str) -> Result<(), JsValue> {
+        let username = ShortIdStr::new(username)
+            .map_err(|e| JsValue::from_str(&format!("Invalid username: {e}")))?;
+
+        self.tx
+            .clone()
+            .send(ClientEvent::CancelConnect { username })
+            .await
+            .expect("Unreachable: Client event receiver was dropped");
+
+        Ok(())
+    }
+
+    /// Sets the handler for WebRTC signals, called with `(from, kind, payload)`
+    /// where `kind` is one of `offer`, `answer` or `candidate`
+    #[wasm_bindgen(js_name = "onSignal")]
+    pub fn on_signal(&self, handler: js_sys::Function) {
+        *self.on_signal.borrow_mut() = Some(handler);
+    }
+
+    /// Sends a WebRTC signal to a user who accepted to connect, `kind` is one of
+    /// `offer`, `answer` or `candidate`, and `payload` the SDP or JSON encoded ICE candidate
+    #[wasm_bindgen(js_name = "sendSignal")]
+    pub async fn send_signal(
+        &self,
+        username: &str,
+        kind: &str,
+        payload: &str,
+    ) -> Result<(), JsValue> {
+        let to = ShortIdStr::new(username)
+            .map_err(|e| JsValue::from_str(&format!("Invalid username: {e}")))?;
+        let payload = BoundedBytes::new(payload.as_bytes())
+            .map_err(|e| JsValue::from_str(&format!("Invalid signal payload: {e}")))?;
+
+        let signal = match kind {
+            "offer" => Signal::Offer(payload),
+            "answer" => Signal::Answer(payload),
+            "candidate" => Signal::IceCandidate(payload),
+            kind => return Err(JsValue::from_str(&format!("Unknown signal kind: {kind}"))),
+        };
+
+        self.tx
+            .clone()
+            .send(ClientEvent::Signal(C2SSignal { to, signal }))
+            .await
+            .expect("Unreachable: Client event receiver was dropped");
+
+        Ok(())
+    }
+
+    /// Sets the handler for relayed frames, called with `(from, frame)`
+    #[wasm_bindgen(js_name = "onRelayFrame")]
+    pub fn on_relay_frame(&self, handler: js_sys::Function) {
+        *self.on_relay_frame.borrow_mut() = Some(handler);
+    }
+
+    /// Sends a frame through servie to a user who accepted to connect,
+    /// for when there is no peer-to-peer path
+    ///
+    /// `frame` must already be end-to-end encrypted, servie forwards it as is
+    #[wasm_bindgen(js_name = "sendRelayFrame")]
+    pub async fn send_relay_frame(&self, username: &str, frame: &[u8]) -> Result<(), JsValue> {
+        let to = ShortIdStr::new(username)
+            .map_err(|e| JsValue::from_str(&format!("Invalid username: {e}")))?;
+        let frame = BoundedBytes::new(frame)
+            .map_err(|e| JsValue::from_str(&format!("Invalid relay frame: {e}")))?;
+
+        self.tx
+            .clone()
+            .send(ClientEvent::RelayFrame(C2SRelayFrame { to, frame }))
             .await
             .expect("Unreachable: Client event receiver was dropped");
-        // self.ws.send_se(ConnectToUser { username })?;
 
         Ok(())
     }
+
+    /// Sets the handler for mail, called with `(from, sentAt, envelope)` where `sentAt`
+    /// is a unix timestamp in seconds
+    ///
+    /// Mail is acknowledged once the handler returns, and delivered again on the next
+    /// login if it throws
+    #[wasm_bindgen(js_name = "onMail")]
+    pub fn on_mail(&self, handler: js_sys::Function) {
+        *self.on_mail.borrow_mut() = Some(handler);
+    }
+
+    /// Leaves mail for `username`, delivered once they are online, even if they are right now
+    ///
+    /// `envelope` must already be end-to-end encrypted, servie stores it as is.
+    /// Resolves with one of `Stored`, `UnknownUser` or `MailboxFull`
+    #[wasm_bindgen(js_name = "sendMail")]
+    pub async fn send_mail(&self, username: &str, envelope: &[u8]) -> Result<String, JsValue> {
+        let to = ShortIdStr::new(username)
+            .map_err(|e| JsValue::from_str(&format!("Invalid username: {e}")))?;
+        let envelope = MailEnvelope::new(envelope)
+            .map_err(|e| JsValue::from_str(&format!("Invalid mail envelope: {e}")))?;
+
+        let (reply, result) = oneshot::channel();
+        self.tx
+            .clone()
+            .send(ClientEvent::SendMail {
+                to,
+                envelope,
+                reply,
+            })
+            .await
+            .expect("Unreachable: Client event receiver was dropped");
+
+        let result = result
+            .await
+            .map_err(|_| JsValue::from_str("Connection to servie closed"))?;
+        Ok(format!("{result:?}"))
+    }
+
+    /// Sets the handler for presence updates, called with `(username, online)`
+    #[wasm_bindgen(js_name = "onPresence")]
+    pub fn on_presence(&self, handler: js_sys::Function) {
+        *self.on_presence.borrow_mut() = Some(handler);
+    }
+
+    /// Replaces the users whose presence is reported to `onPresence`,
+    /// their current presence is reported right away
+    #[wasm_bindgen(js_name = "subscribePresence")]
+    pub async fn subscribe_presence(&self, usernames: Vec<String>) -> Result<(), JsValue> {
+        let usernames = usernames
+            .iter()
+            .map(ShortIdStr::new)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| JsValue::from_str(&format!("Invalid username: {e}")))?;
+        let usernames = BoundedVec::new(usernames).map_err(|_| {
+            JsValue::from_str(&format!(
+                "Can't subscribe to more than {MAX_PRESENCE_SUBSCRIPTIONS} users"
+            ))
+        })?;
+
+        self.tx
+            .clone()
+            .send(ClientEvent::SubscribePresence(C2SSubscribePresence {
+                usernames,
+            }))
+            .await
+            .expect("Unreachable: Client event receiver was dropped");
+
+        Ok(())
+    }
+
+    /// Appears offline to everyone subscribed to our presence, or online again
+    #[wasm_bindgen(js_name = "setPresenceHidden")]
+    pub async fn set_presence_hidden(&self, hidden: bool) {
+        self.tx
+            .clone()
+            .send(ClientEvent::SetPresence(C2SSetPresence { hidden }))
+            .await
+            .expect("Unreachable: Client event receiver was dropped");
+    }
+
+    /// Blocks `username`, or unblocks them if `blocked` is `false`
+    ///
+    /// Blocked users see us as offline, and their mail to us is dropped.
+    /// Resolves with one of `Done` or `ListFull`
+    #[wasm_bindgen(js_name = "setBlocked")]
+    pub async fn set_blocked(&self, username: &str, blocked: bool) -> Result<String, JsValue> {
+        let username = ShortIdStr::new(username)
+            .map_err(|e| JsValue::from_str(&format!("Invalid username: {e}")))?;
+
+        let (reply, result) = oneshot::channel();
+        self.tx
+            .clone()
+            .send(ClientEvent::SetBlocked {
+                username,
+                blocked,
+                reply,
+            })
+            .await
+            .expect("Unreachable: Client event receiver was dropped");
+
+        let result = result
+            .await
+            .map_err(|_| JsValue::from_str("Connection to servie closed"))?;
+        Ok(format!("{result:?}"))
+    }
+
+    /// Resolves with the users we block
+    #[wasm_bindgen(js_name = "getBlockList")]
+    pub async fn get_block_list(&self) -> Result<Vec<String>, JsValue> {
+        let (reply, result) = oneshot::channel();
+        self.tx
+            .clone()
+            .send(ClientEvent::GetBlockList { reply })
+            .await
+            .expect("Unreachable: Client event receiver was dropped");
+
+        let usernames = result
+            .await
+            .map_err(|_| JsValue::from_str("Connection to servie closed"))?;
+        Ok(usernames
+            .iter()
+            .map(|username| username.to_string())
+            .collect())
+    }
+
+    /// Only takes connection requests from contacts, or from everyone again
+    ///
+    /// Anyone else sees us as offline, but can still send a contact request
+    #[wasm_bindgen(js_name = "setContactsOnly")]
+    pub async fn set_contacts_only(&self, enabled: bool) {
+        self.tx
+            .clone()
+            .send(ClientEvent::SetContactsOnly(C2SSetContactsOnly { enabled }))
+            .await
+            .expect("Unreachable: Client event receiver was dropped");
+    }
+
+    /// Adds `username` to our contacts, or removes them if `contact` is `false`
+    ///
+    /// Resolves with one of `Done` or `ListFull`
+    #[wasm_bindgen(js_name = "setContact")]
+    pub async fn set_contact(&self, username: &str, contact: bool) -> Result<String, JsValue> {
+        let username = ShortIdStr::new(username)
+            .map_err(|e| JsValue::from_str(&format!("Invalid username: {e}")))?;
+
+        let (reply, result) = oneshot::channel();
+        self.tx
+            .clone()
+            .send(ClientEvent::SetContact {
+                username,
+                contact,
+                reply,
+            })
+            .await
+            .expect("Unreachable: Client event receiver was dropped");
+
+        let result = result
+            .await
+            .map_err(|_| JsValue::from_str("Connection to servie closed"))?;
+        Ok(format!("{result:?}"))
+    }
+
+    /// Resolves with our contacts
+    #[wasm_bindgen(js_name = "getContacts")]
+    pub async fn get_contacts(&self) -> Result<Vec<String>, JsValue> {
+        let contacts = self.contacts().await?;
+        Ok(contacts
+            .usernames
+            .into_iter()
+            .map(|username| username.as_str().to_owned())
+            .collect())
+    }
+
+    /// Resolves with whether we only take connection requests from contacts
+    #[wasm_bindgen(js_name = "isContactsOnly")]
+    pub async fn is_contacts_only(&self) -> Result<bool, JsValue> {
+        Ok(self.contacts().await?.contacts_only)
+    }
+
+    async fn contacts(&self) -> Result<S2CContacts, JsValue> {
+        let (reply, result) = oneshot::channel();
+        self.tx
+            .clone()
+            .send(ClientEvent::GetContacts { reply })
+            .await
+            .expect("Unreachable: Client event receiver was dropped");
+
+        result
+            .await
+            .map_err(|_| JsValue::from_str("Connection to servie closed"))
+    }
+
+    /// Sets the handler for contact requests, called with `(username)`
+    ///
+    /// Accepting is adding them with `setContact`, requests left unanswered come again
+    /// on the next login
+    #[wasm_bindgen(js_name = "onContactRequest")]
+    pub fn on_contact_request(&self, handler: js_sys::Function) {
+        *self.on_contact_request.borrow_mut() = Some(handler);
+    }
+
+    /// Asks `username` to add us to their contacts
+    ///
+    /// Resolves with `Sent` or `RateLimited`, `Sent` doesn't tell whether they are online
+    #[wasm_bindgen(js_name = "sendContactRequest")]
+    pub async fn send_contact_request(&self, username: &str) -> Result<String, JsValue> {
+        let username = ShortIdStr::new(username)
+            .map_err(|e| JsValue::from_str(&format!("Invalid username: {e}")))?;
+
+        let (reply, result) = oneshot::channel();
+        self.tx
+            .clone()
+            .send(ClientEvent::ContactRequest { username, reply })
+            .await
+            .expect("Unreachable: Client event receiver was dropped");
+
+        let result = result
+            .await
+            .map_err(|_| JsValue::from_str("Connection to servie closed"))?;
+        Ok(format!("{result:?}"))
+    }
 }
@@ -1,6 +1,6 @@
 use std::cell::RefCell;
 
-use schemou::Sirius;
+use schemou::{S2CError, Sirius, S2C};
 
 use futures::{
     channel::{mpsc, oneshot},
@@ -67,13 +67,18 @@ impl WebSocket {
             .data()
             .dyn_into()
             .map_err(|_| JsValue::from_str("MessageEvent data is not an ArrayBuffer"))?;
-        let uint8_array = js_sys::Uint8Array::new(&array_buffer);
+        let data = js_sys::Uint8Array::new(&array_buffer).to_vec();
 
-        let deserialized_t = T::deserialize(&uint8_array.to_vec())
-            .map(|(t, _)| t)
-            .map_err(|e| JsValue::from_str(&format!("Deserialization error: {}", e)));
+        schemou::decode(&data)
+            .map_err(|e| JsValue::from_str(&format!("Deserialization error: {}", e)))
+    }
 
-        deserialized_t
+    /// Receives the next message from servie, surfacing `S2CError` as a JS error
+    pub async fn recv_s2c(&mut self) -> Result<S2C, JsValue> {
+        match self.recv_de().await? {
+            S2C::Error(error) => Err(server_error(error)),
+            msg => Ok(msg),
+        }
     }
 
     fn send(&self, data: &[u8]) -> Result<(), JsValue> {
@@ -88,6 +93,13 @@ impl WebSocket {
     }
 }
 
+/// Converts an `S2CError` into a JS `Error` with the error code as its `name`
+fn server_error(S2CError { code, reason }: S2CError) -> JsValue {
+    let error = js_sys::Error::new(&reason);
+    error.set_name(&format!("{code:?}"));
+    error.into()
+}
+
 impl Drop for WebSocket {
     fn drop(&mut self) {
         _ = self.ws.close();
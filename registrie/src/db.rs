@@ -1,45 +1,87 @@
-use std::sync::Arc;
+use std::{io, sync::Arc};
 
-use git2::{Oid, Repository, Signature};
+use git2::{Oid, Repository};
 use schemou::legos::ShortIdStr;
 use tokio::sync::Mutex;
 
 use crate::erout;
-use registrie::*;
+use crate::errors::*;
+use registrie::{
+    signing::{generate_signing_key, load_signing_key},
+    *,
+};
 
 #[derive(Clone)]
 pub struct DB {
     git: Arc<Mutex<Repository>>,
+    key: Arc<SigningKey>,
 }
 
 impl DB {
     // This function blocks on fs operations
     // That's fine as it's called once at the very start
-    pub fn get_or_create(path: &str) -> Self {
-        let git = Arc::new(Mutex::new(
-            Repository::open_bare(path).unwrap_or_else(|_| DB::init_repo(path).unwrap()),
-        ));
+    pub fn get_or_create(path: &str, key_path: &str) -> io::Result<Self> {
+        let (repo, key) = match Repository::open_bare(path) {
+            // Records signed so far only verify against the key that signed them,
+            // so a missing key is never replaced for an existing DB
+            Ok(repo) => {
+                let key = load_signing_key(key_path).map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!("signing key {key_path} of the existing DB: {err}"),
+                    )
+                })?;
+                (repo, key)
+            }
+            Err(_) => {
+                let key = match load_signing_key(key_path) {
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                        generate_signing_key(key_path)?
+                    }
+                    key => key?,
+                };
+                (DB::init_repo(path, &key).unwrap(), key)
+            }
+        };
 
         tracing::info!("openned git database repo");
-        Self { git }
+        Ok(Self {
+            git: Arc::new(Mutex::new(repo)),
+            key: Arc::new(key),
+        })
+    }
+
+    pub async fn new_record(
+        &self,
+        username: ShortIdStr,
+        pubkey: Box<[u8]>,
+    ) -> RegistrieResult<Oid> {
+        new_record(self.git.clone(), self.key.clone(), username, pubkey)
+            .await
+            .expect("Git database not accessible")
+            .ok_or(RegistrieError::UsernameTaken)
     }
 
-    pub async fn new_record(&self, username: ShortIdStr, pubkey: Box<[u8]>) -> Oid {
-        new_record(self.git.clone(), username, pubkey)
+    pub async fn lookup_record(&self, username: ShortIdStr) -> (Oid, Option<Record>) {
+        lookup_record_at(self.git.clone(), username)
             .await
             .expect("Git database not accessible")
     }
 
-    fn init_repo(path: &str) -> Result<Repository, git2::Error> {
+    pub async fn record_exists(&self, username: ShortIdStr) -> bool {
+        record_exists(self.git.clone(), username)
+            .await
+            .expect("Git database not accessible")
+    }
+
+    fn init_repo(path: &str, key: &SigningKey) -> Result<Repository, git2::Error> {
         tracing::info!("initializing new git database repo");
         let repo = Repository::init_bare(path).expect("OS");
         {
-            let sig = Signature::now(AUTHOR, AUTHOR)?;
             let tree = repo.find_tree(repo.treebuilder(None)?.write()?)?;
-            let commit = repo.find_commit(erout!(repo.commit(
-                None,
-                &sig,
-                &sig,
+            let commit = repo.find_commit(erout!(commit_signed(
+                &repo,
+                key,
                 "Initial Commit",
                 &tree,
                 &[]
@@ -54,27 +96,52 @@ impl DB {
 #[cfg(test)]
 mod db_tests {
     use base64::{prelude::BASE64_STANDARD, Engine};
-    use registrie::lookup_record;
+    use registrie::{lookup_record, signing::load_verifying_key, verify_history};
     use schemou::legos::ShortIdStr;
     use tokio::task::spawn_blocking;
 
-    use super::DB;
+    use super::{generate_signing_key, DB};
+    use crate::errors::RegistrieError;
     use std::fs;
 
+    /// Opens the DB at `path`, with its signing key next to it
+    async fn open(path: &str) -> std::io::Result<DB> {
+        let path = path.to_string();
+        spawn_blocking(move || DB::get_or_create(&path, &format!("{path}.key")))
+            .await
+            .unwrap()
+    }
+
+    fn cleanup(path: &str) {
+        fs::remove_dir_all(path).unwrap();
+        _ = fs::remove_file(format!("{path}.key"));
+        _ = fs::remove_file(format!("{path}.key.pub"));
+    }
+
     #[tokio::test]
     async fn new_record() {
         let path = rand::random::<u64>().to_string();
-        let db = {
-            let path = path.clone();
-            spawn_blocking(move || DB::get_or_create(&path))
-                .await
-                .unwrap()
-        };
+        let db = open(&path).await.unwrap();
+        let verifying_key = load_verifying_key(&format!("{path}.key.pub")).unwrap();
 
         let username = ShortIdStr::new("duskyelf").unwrap();
         let pubkey: Box<[u8]> = [1, 2, 3, 13, 42].into();
 
-        db.new_record(username.clone(), pubkey.clone()).await;
+        assert!(!db.record_exists(username.clone()).await);
+
+        let commit_id = db
+            .new_record(username.clone(), pubkey.clone())
+            .await
+            .unwrap();
+
+        assert!(db.record_exists(username.clone()).await);
+        assert_eq!(db.lookup_record(username.clone()).await.0, commit_id);
+
+        // Every commit including the initial one is signed
+        assert_eq!(
+            verify_history(&*db.git.lock().await, commit_id, None, &verifying_key).unwrap(),
+            2
+        );
 
         let record = lookup_record(db.git, username.clone())
             .await
@@ -87,6 +154,64 @@ mod db_tests {
             BASE64_STANDARD.decode(record.pubkey).unwrap().into()
         );
 
-        fs::remove_dir_all(path).unwrap();
+        cleanup(&path);
+    }
+
+    #[tokio::test]
+    async fn duplicate_record() {
+        let path = rand::random::<u64>().to_string();
+        let db = open(&path).await.unwrap();
+
+        let username = ShortIdStr::new("duskyelf").unwrap();
+        let pubkey: Box<[u8]> = [1, 2, 3, 13, 42].into();
+        let other_pubkey: Box<[u8]> = [4, 5, 6].into();
+
+        db.new_record(username.clone(), pubkey.clone())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            db.new_record(username.clone(), other_pubkey).await,
+            Err(RegistrieError::UsernameTaken)
+        ));
+
+        let record = lookup_record(db.git, username).await.unwrap().unwrap();
+        assert_eq!(
+            pubkey,
+            BASE64_STANDARD.decode(record.pubkey).unwrap().into()
+        );
+
+        cleanup(&path);
+    }
+
+    #[tokio::test]
+    async fn missing_key() {
+        let path = rand::random::<u64>().to_string();
+        drop(open(&path).await.unwrap());
+
+        // The key is only generated along with a new DB
+        fs::remove_file(format!("{path}.key")).unwrap();
+        assert!(open(&path).await.is_err());
+        assert!(!fs::exists(format!("{path}.key")).unwrap());
+
+        cleanup(&path);
+    }
+    #[tokio::test]
+    async fn private_key() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = rand::random::<u64>().to_string();
+        let key_path = format!("{path}.key");
+        drop(open(&path).await.unwrap());
+
+        let mode = fs::metadata(&key_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // An existing key is never replaced
+        let key = fs::read(&key_path).unwrap();
+        assert!(generate_signing_key(&key_path).is_err());
+        assert_eq!(fs::read(&key_path).unwrap(), key);
+
+        cleanup(&path);
     }
 }
@@ -1,20 +1,43 @@
-use axum::response::{IntoResponse, Response};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
 
 pub type RegistrieResult<T> = Result<T, RegistrieError>;
 
 #[derive(thiserror::Error, Debug)]
-pub enum RegistrieError {}
+pub enum RegistrieError {
+    #[error("Username is already registered")]
+    UsernameTaken,
+
+    #[error("Username is not registered")]
+    UserNotFound,
+
+    #[error("Invalid username: {0}")]
+    InvalidUsername(schemou::SiriusError),
+
+    #[error("Registration signature does not match the pubkey")]
+    InvalidSignature,
+
+    #[error("Record of {0} holds a corrupted pubkey")]
+    CorruptedRecord(String),
+}
 
 impl IntoResponse for RegistrieError {
     fn into_response(self) -> Response {
-        // Log the error with its details (for debugging)
-        tracing::error!("Internal server error: {:?}", self);
-
-        // Return a generic 500 response without exposing internal details
-        (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            "Internal server error".to_string(),
-        )
-            .into_response()
+        let status = match self {
+            RegistrieError::UsernameTaken => StatusCode::CONFLICT,
+            RegistrieError::UserNotFound => StatusCode::NOT_FOUND,
+            RegistrieError::InvalidUsername(_) | RegistrieError::InvalidSignature => {
+                StatusCode::BAD_REQUEST
+            }
+            RegistrieError::CorruptedRecord(_) => {
+                tracing::error!("{}", self);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+
+        tracing::debug!("Rejected request: {}", self);
+        (status, self.to_string()).into_response()
     }
 }
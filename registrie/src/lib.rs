@@ -1,9 +1,14 @@
-use schemou::legos::ShortIdStr;
+pub mod signing;
+
+pub use signing::{commit_signed, verify_commit, verify_history, SigningKey, VerifyingKey};
+
+use schemou::{legos::ShortIdStr, C2RRegister, REGISTER_CONTEXT};
 
 use std::sync::Arc;
 
 use base64::prelude::*;
-use git2::{build, Error, FileMode, Oid, Repository, Signature};
+use fips204::{ml_dsa_87, traits::*};
+use git2::{build, Error, FileMode, Oid, Repository};
 use tokio::{sync::Mutex, task::spawn_blocking};
 
 pub use nanoserde::{DeRon, SerRon};
@@ -17,6 +22,35 @@ pub struct Record {
     pub pubkey: String,
 }
 
+impl Record {
+    /// Checks an ML-DSA-87 `signature` of `message` against this record's pubkey
+    pub fn verify(&self, message: &[u8], signature: &[u8], ctx: &[u8]) -> bool {
+        let Ok(pubkey) = BASE64_STANDARD.decode(&self.pubkey) else {
+            return false;
+        };
+
+        verify_signature(&pubkey, message, signature, ctx)
+    }
+}
+
+/// Checks an ML-DSA-87 `signature` of `message` against a raw `pubkey`,
+/// malformed keys or signatures never verify
+pub fn verify_signature(pubkey: &[u8], message: &[u8], signature: &[u8], ctx: &[u8]) -> bool {
+    let Some(pubkey) = pubkey
+        .try_into()
+        .ok()
+        .and_then(|pubkey| ml_dsa_87::PublicKey::try_from_bytes(pubkey).ok())
+    else {
+        return false;
+    };
+
+    let Ok(signature) = signature.try_into() else {
+        return false;
+    };
+
+    pubkey.verify(message, signature, ctx)
+}
+
 pub fn record_path(username: &ShortIdStr) -> String {
     if username.len() > 3 {
         format!(
@@ -35,11 +69,13 @@ pub fn record_path(username: &ShortIdStr) -> String {
     }
 }
 
+/// Commits a new record, returns `None` if the username is already registered
 pub async fn new_record(
     git: Arc<Mutex<Repository>>,
+    key: Arc<SigningKey>,
     username: ShortIdStr,
     pubkey: Box<[u8]>,
-) -> Result<Oid, Error> {
+) -> Result<Option<Oid>, Error> {
     let record = Record {
         username: username.to_string(),
         pubkey: BASE64_STANDARD.encode(pubkey),
@@ -57,33 +93,34 @@ pub async fn new_record(
         // So here some other task could use the db
 
         let repo = git.blocking_lock();
-        let sig = Signature::now(AUTHOR, AUTHOR)?;
 
-        let reference = repo
+        let mut reference = repo
             .find_branch(DEFAULT_BRANCH, git2::BranchType::Local)?
             .into_reference();
 
         let last_commit = reference
             .peel_to_commit()
             .expect("Unreachable: no commit on reference");
+        let last_tree = last_commit.tree()?;
+
+        // Registration is first-come, the check has to happen under the same lock as the commit
+        // otherwise two racing registrations could both succeed
+        let path = record_path(&username);
+        if last_tree.get_path(std::path::Path::new(&path)).is_ok() {
+            return Ok(None);
+        }
 
         let tree = repo.find_tree(
             build::TreeUpdateBuilder::new()
-                .upsert(record_path(&username), blob, FileMode::Blob)
-                .create_updated(&repo, &last_commit.tree()?)?,
+                .upsert(path, blob, FileMode::Blob)
+                .create_updated(&repo, &last_tree)?,
         )?;
 
-        // TODO: Sign registrie's git commits
-        // labels: enhancement, good first issue, discussion
-        // Issue URL: https://github.com/Colabie/Colabie/issues/7
-        repo.commit(
-            reference.name(),
-            &sig,
-            &sig,
-            &format!("Register: {}", record.username),
-            &tree,
-            &[&last_commit],
-        )
+        let message = format!("Register: {}", record.username);
+        let commit = commit_signed(&repo, &key, &message, &tree, &[&last_commit])?;
+        reference.set_target(commit, &message)?;
+
+        Ok(Some(commit))
     })
     .await
     .unwrap()
@@ -93,6 +130,14 @@ pub async fn lookup_record(
     git: Arc<Mutex<Repository>>,
     username: ShortIdStr,
 ) -> Result<Option<Record>, Error> {
+    Ok(lookup_record_at(git, username).await?.1)
+}
+
+/// Same as `lookup_record`, but also returns the commit id the answer was read at
+pub async fn lookup_record_at(
+    git: Arc<Mutex<Repository>>,
+    username: ShortIdStr,
+) -> Result<(Oid, Option<Record>), Error> {
     // As git2 operations are blocking, we wrap those with `spawn_blocking()`
     // but then to keep track of the db lock from the async enviorment, ie. `tokio::sync::Mutex`
     // the blocking task waits `git.blocking_lock()` for the mutex lock
@@ -102,18 +147,15 @@ pub async fn lookup_record(
         let path = std::path::Path::new(&path);
         let repo = git.blocking_lock();
 
-        let tree_entry = {
-            let tree = repo
-                .find_branch(DEFAULT_BRANCH, git2::BranchType::Local)?
-                .into_reference()
-                .peel_to_commit()
-                .expect("Unreachable: no commit on reference")
-                .tree()?;
-
-            match tree.get_path(path) {
-                Ok(tree_entry) => tree_entry,
-                Err(_) => return Ok(None),
-            }
+        let commit = repo
+            .find_branch(DEFAULT_BRANCH, git2::BranchType::Local)?
+            .into_reference()
+            .peel_to_commit()
+            .expect("Unreachable: no commit on reference");
+
+        let tree_entry = match commit.tree()?.get_path(path) {
+            Ok(tree_entry) => tree_entry,
+            Err(_) => return Ok((commit.id(), None)),
         };
 
         let record = {
@@ -128,8 +170,86 @@ pub async fn lookup_record(
             Record::deserialize_ron(raw_record).expect("Unreachable: unparsable record")
         };
 
-        Ok::<_, Error>(Some(record))
+        Ok::<_, Error>((commit.id(), Some(record)))
+    })
+    .await
+    .unwrap()
+}
+
+/// Checks if a record exists without reading it
+pub async fn record_exists(
+    git: Arc<Mutex<Repository>>,
+    username: ShortIdStr,
+) -> Result<bool, Error> {
+    spawn_blocking(move || {
+        let path: String = record_path(&username);
+        let repo = git.blocking_lock();
+
+        let tree = repo
+            .find_branch(DEFAULT_BRANCH, git2::BranchType::Local)?
+            .into_reference()
+            .peel_to_commit()
+            .expect("Unreachable: no commit on reference")
+            .tree()?;
+
+        Ok(tree.get_path(std::path::Path::new(&path)).is_ok())
     })
     .await
     .unwrap()
 }
+
+/// Checks the proof of possession sent along with a registration
+pub fn verify_registration(username: &ShortIdStr, pubkey: &[u8], signature: &[u8]) -> bool {
+    verify_signature(
+        pubkey,
+        &C2RRegister::signed_payload(username, pubkey),
+        signature,
+        REGISTER_CONTEXT,
+    )
+}
+
+#[cfg(test)]
+mod record_tests {
+    use super::{verify_registration, Record};
+    use schemou::{legos::ShortIdStr, C2RRegister, REGISTER_CONTEXT};
+
+    use base64::prelude::*;
+    use fips204::{ml_dsa_87, traits::*};
+
+    #[test]
+    fn verify() {
+        let (pubkey, sk_key) = ml_dsa_87::try_keygen().unwrap();
+        let record = Record {
+            username: "duskyelf".to_string(),
+            pubkey: BASE64_STANDARD.encode(pubkey.into_bytes()),
+        };
+
+        let signature = sk_key.try_sign(b"challenge", b"ctx").unwrap();
+
+        assert!(record.verify(b"challenge", &signature, b"ctx"));
+        assert!(!record.verify(b"challenge", &signature, b"other ctx"));
+        assert!(!record.verify(b"other challenge", &signature, b"ctx"));
+        assert!(!record.verify(b"challenge", &signature[1..], b"ctx"));
+    }
+
+    #[test]
+    fn registration() {
+        let (pubkey, sk_key) = ml_dsa_87::try_keygen().unwrap();
+        let (other_pubkey, _) = ml_dsa_87::try_keygen().unwrap();
+        let (pubkey, other_pubkey) = (pubkey.into_bytes(), other_pubkey.into_bytes());
+
+        let username = ShortIdStr::new("duskyelf").unwrap();
+        let other_username = ShortIdStr::new("duskyelf2").unwrap();
+
+        let signature = sk_key
+            .try_sign(
+                &C2RRegister::signed_payload(&username, &pubkey),
+                REGISTER_CONTEXT,
+            )
+            .unwrap();
+
+        assert!(verify_registration(&username, &pubkey, &signature));
+        assert!(!verify_registration(&other_username, &pubkey, &signature));
+        assert!(!verify_registration(&username, &other_pubkey, &signature));
+    }
+}
@@ -5,18 +5,23 @@ mod utils;
 use db::DB;
 use errors::*;
 
-use schemou::*;
+use schemou::{
+    legos::{CommitId, MlDsaPublicKey, ShortIdStr},
+    *,
+};
 
 use axum::{
-    extract::State,
-    http::{header, Method},
-    routing::post,
+    extract::{Path, State},
+    http::{header, Method, StatusCode},
+    routing::{get, post},
     Router,
 };
+use base64::prelude::*;
 use tower_http::{cors, trace::TraceLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 const DB_PATH: &str = "locals/db";
+const SIGNING_KEY_PATH: &str = "locals/signing_key";
 
 #[tokio::main]
 async fn main() {
@@ -29,15 +34,16 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let db = DB::get_or_create(DB_PATH);
+    let db = DB::get_or_create(DB_PATH, SIGNING_KEY_PATH).expect("Could not open the DB");
 
     let cors = cors::CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
+        .allow_methods([Method::GET, Method::HEAD, Method::POST])
         .allow_origin(cors::Any)
         .allow_headers([header::CONTENT_TYPE]);
 
     let router = Router::new()
         .route("/register", post(register))
+        .route("/record/{username}", get(lookup).head(exists))
         .with_state(db)
         .layer(cors)
         .layer(
@@ -53,10 +59,94 @@ async fn main() {
     axum::serve(listner, router).await.unwrap();
 }
 
+/// A registration is a username, a public key and a signature, way below this
+const REGISTER_BODY_LIMIT: usize = 8 * 1024;
+
 async fn register(
     State(db): State<DB>,
-    Schemou(C2RRegister { username, pubkey }): Schemou<C2RRegister>,
+    Schemou(C2RRegister {
+        username,
+        pubkey,
+        signature,
+    }): Schemou<C2RRegister, REGISTER_BODY_LIMIT>,
 ) -> RegistrieResult<Schemou<R2CRegister>> {
-    let commit_id = db.new_record(username, pubkey).await.as_bytes().into();
-    Ok(Schemou(R2CRegister { commit_id }))
+    if !registrie::verify_registration(&username, &pubkey, &signature) {
+        return Err(RegistrieError::InvalidSignature);
+    }
+
+    let commit_id = db.new_record(username, pubkey.into_inner()).await?;
+    Ok(Schemou(R2CRegister {
+        commit_id: commit_id_lego(commit_id),
+    }))
+}
+
+async fn lookup(
+    State(db): State<DB>,
+    Path(username): Path<String>,
+) -> RegistrieResult<Schemou<R2CLookup>> {
+    let username = ShortIdStr::new(username).map_err(RegistrieError::InvalidUsername)?;
+    let (commit_id, record) = db.lookup_record(username).await;
+    let record = record.ok_or(RegistrieError::UserNotFound)?;
+
+    // Records written before pubkeys were checked can hold any length
+    let pubkey = BASE64_STANDARD
+        .decode(&record.pubkey)
+        .ok()
+        .and_then(|pubkey| MlDsaPublicKey::new(pubkey).ok())
+        .ok_or(RegistrieError::CorruptedRecord(record.username))?;
+
+    Ok(Schemou(R2CLookup {
+        pubkey,
+        commit_id: commit_id_lego(commit_id),
+    }))
+}
+
+async fn exists(State(db): State<DB>, Path(username): Path<String>) -> RegistrieResult<StatusCode> {
+    let username = ShortIdStr::new(username).map_err(RegistrieError::InvalidUsername)?;
+    match db.record_exists(username).await {
+        true => Ok(StatusCode::OK),
+        false => Err(RegistrieError::UserNotFound),
+    }
+}
+
+fn commit_id_lego(commit_id: git2::Oid) -> CommitId {
+    CommitId::new(commit_id.as_bytes()).expect("Unreachable: registrie uses SHA-1 commit ids")
+}
+
+#[cfg(test)]
+mod main_tests {
+    use super::{lookup, DB};
+    use crate::errors::RegistrieError;
+
+    use axum::extract::{Path, State};
+    use schemou::legos::ShortIdStr;
+    use tokio::task::spawn_blocking;
+
+    use std::fs;
+
+    #[tokio::test]
+    async fn legacy_record() {
+        let path = rand::random::<u64>().to_string();
+        let db = {
+            let path = path.clone();
+            spawn_blocking(move || DB::get_or_create(&path, &format!("{path}.key")))
+                .await
+                .unwrap()
+                .unwrap()
+        };
+
+        let username = ShortIdStr::new("duskyelf").unwrap();
+        db.new_record(username, [1, 2, 3, 13, 42].into())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            lookup(State(db), Path("duskyelf".to_string())).await,
+            Err(RegistrieError::CorruptedRecord(_))
+        ));
+
+        fs::remove_dir_all(&path).unwrap();
+        fs::remove_file(format!("{path}.key")).unwrap();
+        fs::remove_file(format!("{path}.key.pub")).unwrap();
+    }
 }
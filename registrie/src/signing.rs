@@ -0,0 +1,136 @@
+use crate::AUTHOR;
+
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+use base64::prelude::*;
+use fips204::{ml_dsa_87, traits::*};
+use git2::{Commit, Error, ErrorCode, Oid, Repository, Signature, Tree};
+
+pub use ml_dsa_87::{PrivateKey as SigningKey, PublicKey as VerifyingKey};
+
+/// Commit header registrie stores its signature in
+pub const SIGNATURE_FIELD: &str = "mldsasig";
+
+/// ML-DSA context string for signing registrie's git commits
+pub const COMMIT_CONTEXT: &[u8] = b"colabie/registrie/commit";
+
+/// Loads registrie's signing key from `path`
+pub fn load_signing_key(path: &str) -> io::Result<SigningKey> {
+    let bytes = fs::read(path)?
+        .try_into()
+        .map_err(|_| io::Error::other("signing key has an invalid length"))?;
+    SigningKey::try_from_bytes(bytes).map_err(io::Error::other)
+}
+
+/// Generates a new signing keypair at `path`, failing if a key is already there
+///
+/// The key is only readable by its owner, the public half is written next to it as
+/// `{path}.pub` for mirrors to verify against
+pub fn generate_signing_key(path: &str) -> io::Result<SigningKey> {
+    tracing::info!("generating new registrie signing key");
+    let (pubkey, key) = ml_dsa_87::try_keygen().map_err(io::Error::other)?;
+
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let key = key.into_bytes();
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(&key)?;
+    fs::write(format!("{path}.pub"), pubkey.into_bytes())?;
+
+    SigningKey::try_from_bytes(key).map_err(io::Error::other)
+}
+
+/// Loads registrie's public key, as written by `generate_signing_key`
+pub fn load_verifying_key(path: &str) -> io::Result<VerifyingKey> {
+    let bytes = fs::read(path)?
+        .try_into()
+        .map_err(|_| io::Error::other("verifying key has an invalid length"))?;
+    VerifyingKey::try_from_bytes(bytes).map_err(io::Error::other)
+}
+
+/// Creates a commit signed with registrie's key, without updating any reference
+pub fn commit_signed(
+    repo: &Repository,
+    key: &SigningKey,
+    message: &str,
+    tree: &Tree,
+    parents: &[&Commit],
+) -> Result<Oid, Error> {
+    let sig = Signature::now(AUTHOR, AUTHOR)?;
+    let content = repo.commit_create_buffer(&sig, &sig, message, tree, parents)?;
+    let content = content
+        .as_str()
+        .ok_or_else(|| Error::from_str("non-utf8 commit content"))?;
+
+    let signature = key
+        .try_sign(content.as_bytes(), COMMIT_CONTEXT)
+        .map_err(Error::from_str)?;
+
+    repo.commit_signed(
+        content,
+        &BASE64_STANDARD.encode(signature),
+        Some(SIGNATURE_FIELD),
+    )
+}
+
+/// Checks that a commit carries a valid signature from registrie's key
+pub fn verify_commit(repo: &Repository, commit: Oid, key: &VerifyingKey) -> Result<bool, Error> {
+    let (signature, content) = match repo.extract_signature(&commit, Some(SIGNATURE_FIELD)) {
+        Ok(extracted) => extracted,
+        Err(err) if err.code() == ErrorCode::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    let Some(signature) = signature
+        .as_str()
+        .and_then(|signature| BASE64_STANDARD.decode(signature).ok())
+    else {
+        return Ok(false);
+    };
+
+    let Ok(signature) = signature.as_slice().try_into() else {
+        return Ok(false);
+    };
+
+    Ok(key.verify(&content, signature, COMMIT_CONTEXT))
+}
+
+/// Verifies every commit reachable from `tip` but not from `known`,
+/// fails on the first commit that is not signed by registrie's key
+///
+/// Returns the number of verified commits
+pub fn verify_history(
+    repo: &Repository,
+    tip: Oid,
+    known: Option<Oid>,
+    key: &VerifyingKey,
+) -> Result<usize, Error> {
+    let mut walk = repo.revwalk()?;
+    walk.push(tip)?;
+    if let Some(known) = known {
+        walk.hide(known)?;
+    }
+
+    let mut verified = 0;
+    for commit in walk {
+        let commit = commit?;
+        if !verify_commit(repo, commit, key)? {
+            return Err(Error::from_str(&format!(
+                "commit {commit} is not signed by registrie"
+            )));
+        }
+        verified += 1;
+    }
+
+    Ok(verified)
+}
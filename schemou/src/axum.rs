@@ -1,40 +1,116 @@
 #![cfg(feature = "axum")]
 
 use axum::{
-    body::Bytes,
     extract::{FromRequest, Request},
-    http::StatusCode,
+    http::{header::CONTENT_LENGTH, StatusCode},
     response::{IntoResponse, Response},
 };
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use sirius::SiriusError;
 
-use crate::Sirius;
+use crate::{decode, DecodeError, Sirius};
 
-pub struct Schemou<T: Sirius>(pub T);
+/// Body limit of `Schemou` when the extractor doesn't specify one
+pub const DEFAULT_BODY_LIMIT: usize = 64 * 1024;
 
-impl<T, S> FromRequest<S> for Schemou<T>
+/// Extracts `T` from a request body of at most `LIMIT` bytes, and responds with `T`
+pub struct Schemou<T: Sirius, const LIMIT: usize = DEFAULT_BODY_LIMIT>(pub T);
+
+/// Why a request body couldn't be extracted as a schemou message
+#[derive(Debug, thiserror::Error)]
+pub enum SchemouRejection {
+    #[error("request body is larger than {limit} bytes")]
+    TooLarge { limit: usize },
+
+    #[error("failed to read request body")]
+    BodyRead,
+
+    #[error("request body is truncated")]
+    Truncated,
+
+    #[error("invalid field `{ty_name}`: {error}")]
+    InvalidField {
+        ty_name: &'static str,
+        error: String,
+    },
+
+    #[error("{trailing} trailing bytes after `{ty_name}`")]
+    TrailingData {
+        ty_name: &'static str,
+        trailing: usize,
+    },
+
+    #[error("malformed request body: {0}")]
+    Malformed(String),
+}
+
+impl From<DecodeError> for SchemouRejection {
+    fn from(err: DecodeError) -> Self {
+        match err {
+            DecodeError::Sirius(SiriusError::NotEnoughData) => Self::Truncated,
+            DecodeError::Sirius(SiriusError::ParsingError { ty_name, error }) => {
+                Self::InvalidField { ty_name, error }
+            }
+            DecodeError::Sirius(err) => Self::Malformed(err.to_string()),
+            DecodeError::TrailingData { ty_name, trailing } => {
+                Self::TrailingData { ty_name, trailing }
+            }
+        }
+    }
+}
+
+impl SchemouRejection {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl IntoResponse for SchemouRejection {
+    fn into_response(self) -> Response {
+        tracing::debug!("Rejected schemou body: {}", self);
+        (self.status(), self.to_string()).into_response()
+    }
+}
+
+impl<T, S, const LIMIT: usize> FromRequest<S> for Schemou<T, LIMIT>
 where
     T: Sirius,
     S: Send + Sync,
 {
-    type Rejection = StatusCode;
+    type Rejection = SchemouRejection;
 
-    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
-        let bytes = Bytes::from_request(req, state)
+    async fn from_request(req: Request, _state: &S) -> Result<Self, Self::Rejection> {
+        // Refuse early when the client announces a body that's too large
+        let content_length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse::<usize>().ok());
+        if content_length.is_some_and(|len| len > LIMIT) {
+            return Err(SchemouRejection::TooLarge { limit: LIMIT });
+        }
+
+        // The announced length can't be trusted, so the limit is enforced while reading too
+        let bytes = Limited::new(req.into_body(), LIMIT)
+            .collect()
             .await
-            .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-        // TODO: Check that all bytes are consumed in deserialization
-        // labels: enhancement
-        // Issue URL: https://github.com/Colabie/Colabie/issues/29
-        Ok(Self(
-            T::deserialize(&bytes)
-                .map_err(|_| StatusCode::BAD_REQUEST)?
-                .0,
-        ))
+            .map_err(|err| match err.downcast_ref::<LengthLimitError>() {
+                Some(_) => SchemouRejection::TooLarge { limit: LIMIT },
+                None => {
+                    tracing::debug!("failed to read request body: {err}");
+                    SchemouRejection::BodyRead
+                }
+            })?
+            .to_bytes();
+
+        Ok(Self(decode(&bytes)?))
     }
 }
 
-impl<T> IntoResponse for Schemou<T>
+impl<T, const LIMIT: usize> IntoResponse for Schemou<T, LIMIT>
 where
     T: Sirius,
 {
@@ -42,3 +118,23 @@ where
         self.0.serialize_buffered().into_response()
     }
 }
+
+#[test]
+fn rejection_reasons() {
+    let data = crate::legos::ShortIdStr::new("duskyelf")
+        .unwrap()
+        .serialize_buffered();
+
+    let truncated = decode::<crate::legos::ShortIdStr>(&data[..data.len() - 1]).unwrap_err();
+    assert!(matches!(
+        SchemouRejection::from(truncated),
+        SchemouRejection::Truncated
+    ));
+
+    let too_large = SchemouRejection::TooLarge { limit: 1 };
+    assert_eq!(too_large.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(
+        too_large.into_response().status(),
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+}
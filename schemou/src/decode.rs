@@ -0,0 +1,41 @@
+use sirius::{Sirius, SiriusError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error(transparent)]
+    Sirius(#[from] SiriusError),
+
+    #[error("{trailing} trailing bytes after decoding `{ty_name}`")]
+    TrailingData {
+        ty_name: &'static str,
+        trailing: usize,
+    },
+}
+
+/// Decodes a whole frame into `T`, leftover bytes are an error rather than silently ignored
+pub fn decode<T: Sirius>(data: &[u8]) -> Result<T, DecodeError> {
+    let (decoded, len) = T::deserialize(data)?;
+
+    if len != data.len() {
+        return Err(DecodeError::TrailingData {
+            ty_name: std::any::type_name::<T>(),
+            trailing: data.len() - len,
+        });
+    }
+
+    Ok(decoded)
+}
+
+#[test]
+fn trailing_data() {
+    let mut data = crate::legos::ShortIdStr::new("duskyelf")
+        .unwrap()
+        .serialize_buffered();
+    assert!(decode::<crate::legos::ShortIdStr>(&data).is_ok());
+
+    data.push(0);
+    assert!(matches!(
+        decode::<crate::legos::ShortIdStr>(&data),
+        Err(DecodeError::TrailingData { trailing: 1, .. })
+    ));
+}
@@ -0,0 +1,85 @@
+use sirius::{Sirius, SiriusError};
+
+/// Byte string of `MIN..=MAX` bytes, serialized with a `u32` length prefix, so `MAX` must fit a u32
+///
+/// The length is checked before anything is allocated,
+/// so a peer can't make the receiver hold arbitrarily large blobs
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct BoundedBytes<const MIN: usize, const MAX: usize>(Box<[u8]>);
+
+impl<const MIN: usize, const MAX: usize> BoundedBytes<MIN, MAX> {
+    pub fn new(bytes: impl Into<Box<[u8]>>) -> Result<Self, SiriusError> {
+        let bytes = bytes.into();
+        Self::check_len(bytes.len())?;
+        Ok(Self(bytes))
+    }
+
+    pub fn into_inner(self) -> Box<[u8]> {
+        self.0
+    }
+
+    fn check_len(len: usize) -> Result<(), SiriusError> {
+        if len < MIN || len > MAX {
+            return Err(SiriusError::ParsingError {
+                ty_name: "BoundedBytes",
+                error: format!("length {len} out of bounds {MIN}..={MAX}"),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl<const MIN: usize, const MAX: usize> std::ops::Deref for BoundedBytes<MIN, MAX> {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<const MIN: usize, const MAX: usize> Sirius for BoundedBytes<MIN, MAX> {
+    fn serialize(&self, output: &mut impl std::io::Write) -> Result<usize, SiriusError> {
+        // SAFETY: length is already checked in `BoundedBytes::new(..)`
+        output.write_all(&(self.0.len() as u32).to_le_bytes())?;
+        output.write_all(&self.0)?;
+
+        Ok(self.0.len() + 4)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<(Self, usize), SiriusError> {
+        let len = data
+            .get(..4)
+            .ok_or(SiriusError::NotEnoughData)?
+            .try_into()
+            .map(u32::from_le_bytes)
+            .expect("Unreachable: slice of 4 bytes") as usize;
+        Self::check_len(len)?;
+
+        let bytes = data.get(4..len + 4).ok_or(SiriusError::NotEnoughData)?;
+        Ok((Self(bytes.into()), len + 4))
+    }
+}
+
+#[test]
+fn bounds_check() {
+    type Bytes = BoundedBytes<1, 4>;
+
+    assert!(matches!(Bytes::new([1, 2, 3]), Ok(..)));
+    assert!(matches!(
+        Bytes::new([]),
+        Err(SiriusError::ParsingError { .. })
+    ));
+    assert!(matches!(
+        Bytes::new([1, 2, 3, 4, 5]),
+        Err(SiriusError::ParsingError { .. })
+    ));
+
+    let serialized = Bytes::new([1, 2, 3]).unwrap().serialize_buffered();
+    assert!(matches!(Bytes::deserialize(&serialized), Ok((_, 7))));
+
+    // A huge length prefix is rejected without reading further
+    assert!(matches!(
+        Bytes::deserialize(&u32::MAX.to_le_bytes()),
+        Err(SiriusError::ParsingError { .. })
+    ));
+}
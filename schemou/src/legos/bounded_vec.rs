@@ -0,0 +1,101 @@
+use sirius::{Sirius, SiriusError};
+
+/// List of at most `MAX` items, serialized with a `u32` length prefix, so `MAX` must fit a u32
+///
+/// The length is checked before any item is decoded,
+/// so a peer can't make the receiver hold arbitrarily long lists
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct BoundedVec<T, const MAX: usize>(Vec<T>);
+
+impl<T, const MAX: usize> BoundedVec<T, MAX> {
+    pub fn new(items: impl Into<Vec<T>>) -> Result<Self, SiriusError> {
+        let items = items.into();
+        Self::check_len(items.len())?;
+        Ok(Self(items))
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.0
+    }
+
+    fn check_len(len: usize) -> Result<(), SiriusError> {
+        if len > MAX {
+            return Err(SiriusError::ParsingError {
+                ty_name: "BoundedVec",
+                error: format!("length {len} over the maximum {MAX}"),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl<T, const MAX: usize> std::ops::Deref for BoundedVec<T, MAX> {
+    type Target = [T];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T, const MAX: usize> IntoIterator for BoundedVec<T, MAX> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<T: Sirius, const MAX: usize> Sirius for BoundedVec<T, MAX> {
+    fn serialize(&self, output: &mut impl std::io::Write) -> Result<usize, SiriusError> {
+        // SAFETY: length is already checked in `BoundedVec::new(..)`
+        output.write_all(&(self.0.len() as u32).to_le_bytes())?;
+
+        let mut written = 4;
+        for item in &self.0 {
+            written += item.serialize(output)?;
+        }
+        Ok(written)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<(Self, usize), SiriusError> {
+        let len = data
+            .get(..4)
+            .ok_or(SiriusError::NotEnoughData)?
+            .try_into()
+            .map(u32::from_le_bytes)
+            .expect("Unreachable: slice of 4 bytes") as usize;
+        Self::check_len(len)?;
+
+        let mut items = Vec::with_capacity(len);
+        let mut read = 4;
+        for _ in 0..len {
+            let (item, size) = T::deserialize(&data[read..])?;
+            items.push(item);
+            read += size;
+        }
+        Ok((Self(items), read))
+    }
+}
+
+#[test]
+fn bounds_check() {
+    type Items = BoundedVec<u16, 4>;
+
+    assert!(matches!(Items::new([]), Ok(..)));
+    assert!(matches!(
+        Items::new([1, 2, 3, 4, 5]),
+        Err(SiriusError::ParsingError { .. })
+    ));
+
+    let serialized = Items::new([1, 2, 3]).unwrap().serialize_buffered();
+    assert!(matches!(
+        Items::deserialize(&serialized),
+        Ok((items, 10)) if *items == [1, 2, 3]
+    ));
+
+    // A length prefix over the maximum is rejected without decoding any item
+    assert!(matches!(
+        Items::deserialize(&5u32.to_le_bytes()),
+        Err(SiriusError::ParsingError { .. })
+    ));
+}
@@ -0,0 +1,83 @@
+use sirius::{Sirius, SiriusError};
+
+/// Declares a byte string lego of exactly `$len` bytes, serialized without a length prefix
+macro_rules! fixed_bytes {
+    ($(#[$meta:meta])* $name:ident, $len:expr) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Hash, PartialEq, Eq)]
+        pub struct $name(Box<[u8]>);
+
+        impl $name {
+            pub const LEN: usize = $len;
+
+            pub fn new(bytes: impl Into<Box<[u8]>>) -> Result<Self, SiriusError> {
+                let bytes = bytes.into();
+                if bytes.len() != Self::LEN {
+                    return Err(SiriusError::ParsingError {
+                        ty_name: stringify!($name),
+                        error: format!("expected {} bytes, got {}", Self::LEN, bytes.len()),
+                    });
+                }
+
+                Ok(Self(bytes))
+            }
+
+            pub fn into_inner(self) -> Box<[u8]> {
+                self.0
+            }
+        }
+
+        impl std::ops::Deref for $name {
+            type Target = [u8];
+            fn deref(&self) -> &Self::Target {
+                &self.0
+            }
+        }
+
+        impl Sirius for $name {
+            fn serialize(&self, output: &mut impl std::io::Write) -> Result<usize, SiriusError> {
+                // SAFETY: length is already checked in `new(..)`
+                output.write_all(&self.0)?;
+                Ok(Self::LEN)
+            }
+
+            fn deserialize(data: &[u8]) -> Result<(Self, usize), SiriusError> {
+                let bytes = data.get(..Self::LEN).ok_or(SiriusError::NotEnoughData)?;
+                Ok((Self(bytes.into()), Self::LEN))
+            }
+        }
+    };
+}
+
+fixed_bytes!(
+    /// Encoded ML-DSA-87 public key
+    MlDsaPublicKey,
+    2592
+);
+
+fixed_bytes!(
+    /// Encoded ML-DSA-87 signature
+    MlDsaSignature,
+    4627
+);
+
+fixed_bytes!(
+    /// Git (SHA-1) commit id of registrie's database
+    CommitId,
+    20
+);
+
+#[test]
+fn length_check() {
+    assert!(matches!(CommitId::new([0; 20]), Ok(..)));
+    assert!(matches!(
+        CommitId::new([0; 19]),
+        Err(SiriusError::ParsingError { .. })
+    ));
+
+    assert!(matches!(
+        CommitId::deserialize(&[0; 19]),
+        Err(SiriusError::NotEnoughData)
+    ));
+    assert!(matches!(CommitId::deserialize(&[0; 21]), Ok((_, 20))));
+}
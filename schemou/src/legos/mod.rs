@@ -1,3 +1,9 @@
+mod bounded_bytes;
+mod bounded_vec;
+mod fixed_bytes;
 mod short_id_str;
 
+pub use bounded_bytes::BoundedBytes;
+pub use bounded_vec::BoundedVec;
+pub use fixed_bytes::{CommitId, MlDsaPublicKey, MlDsaSignature};
 pub use short_id_str::ShortIdStr;
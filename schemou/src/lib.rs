@@ -1,31 +1,168 @@
 pub mod legos;
 
 mod axum;
+mod decode;
 
 #[cfg(feature = "axum")]
-pub use axum::Schemou;
+pub use axum::{Schemou, SchemouRejection, DEFAULT_BODY_LIMIT};
+pub use decode::{decode, DecodeError};
 pub use sirius::Sirius;
 pub use sirius::SiriusError;
 
 const AUTH_SIZE: usize = 2048;
 
+/// Version of the clientie <-> servie protocol, peers only talk if their versions match
+///
+/// Bumped on every change to the encoding of `C2S` or `S2C`, adding a message or variant
+/// included, since an older peer would misread it rather than reject it
+pub const PROTOCOL_VERSION: u16 = 16;
+
+/// Correlates a request with its reply on a connection, chosen by the side sending the request
+///
+/// Clientie and servie number their requests independently, the direction of a reply tells
+/// whose request it answers
+pub type RequestId = u32;
+
+/// Tells apart the sessions of a user logged in from several devices at once
+///
+/// Chosen by the clientie, and kept across its logins
+pub type DeviceId = u64;
+
+/// ML-DSA context string for signing the servie login challenge
+pub const AUTH_CONTEXT: &[u8] = b"colabie/servie/auth";
+
+/// ML-DSA context string for signing `C2RRegister::signed_payload`
+pub const REGISTER_CONTEXT: &[u8] = b"colabie/registrie/register";
+
 #[derive(Sirius, Debug)]
 pub struct C2RRegister {
     pub username: legos::ShortIdStr,
-    // TODO: All schemou types should be Hardened and have explicit invarients, no generic de-serialization
-    // labels: enhancement, help wanted
-    // Issue URL: https://github.com/Colabie/Colabie/issues/21
-    pub pubkey: Box<[u8]>,
+    pub pubkey: legos::MlDsaPublicKey,
+    /// Proof of possession, ML-DSA-87 signature of `C2RRegister::signed_payload`
+    /// under `REGISTER_CONTEXT`
+    pub signature: legos::MlDsaSignature,
+}
+
+impl C2RRegister {
+    /// Encoding of the registration claim that the user signs with the registered key
+    ///
+    /// Both fields are length prefixed, so no two distinct claims share an encoding
+    pub fn signed_payload(username: &legos::ShortIdStr, pubkey: &[u8]) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(1 + username.len() + 4 + pubkey.len());
+        payload.push(username.len() as u8);
+        payload.extend_from_slice(username.as_bytes());
+        payload.extend_from_slice(&(pubkey.len() as u32).to_le_bytes());
+        payload.extend_from_slice(pubkey);
+        payload
+    }
 }
 
 #[derive(Sirius, Debug)]
 pub struct R2CRegister {
-    pub commit_id: Box<[u8]>,
+    pub commit_id: legos::CommitId,
+}
+
+#[derive(Sirius, Debug)]
+pub struct R2CLookup {
+    pub pubkey: legos::MlDsaPublicKey,
+    /// Registrie commit the record was read at
+    pub commit_id: legos::CommitId,
+}
+
+/// Declares a top level envelope enum, and `From` conversions of its messages into it
+macro_rules! envelope {
+    ($(#[$meta:meta])* $envelope:ident { $($variant:ident($msg:ty)),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Sirius, Debug)]
+        pub enum $envelope {
+            $($variant($msg)),*
+        }
+
+        $(
+            impl From<$msg> for $envelope {
+                fn from(msg: $msg) -> Self {
+                    Self::$variant(msg)
+                }
+            }
+        )*
+    };
+}
+
+envelope! {
+    /// Every message a clientie sends to servie
+    C2S {
+        Hello(C2SHello),
+        Ack(C2SAck),
+        Resume(C2SResume),
+        AuthRes(C2SAuthRes),
+        ConnectToUser(ConnectToUser),
+        ConnectToUserResult(C2SConnectToUserReply),
+        CancelConnect(C2SCancelConnect),
+        Signal(C2SSignal),
+        RelayFrame(C2SRelayFrame),
+        SendMail(C2SSendMail),
+        MailAck(C2SMailAck),
+        SubscribePresence(C2SSubscribePresence),
+        SetPresence(C2SSetPresence),
+        SetBlocked(C2SSetBlocked),
+        GetBlockList(C2SGetBlockList),
+        SetContactsOnly(C2SSetContactsOnly),
+        SetContact(C2SSetContact),
+        GetContacts(C2SGetContacts),
+        ContactRequest(C2SContactRequest),
+    }
+}
+
+envelope! {
+    /// Every message servie sends to a clientie
+    // Messages are short lived, not worth boxing `S2CAuthReq` for
+    #[allow(clippy::large_enum_variant)]
+    S2C {
+        Hello(S2CHello),
+        Error(S2CError),
+        AuthReq(S2CAuthReq),
+        AuthResult(S2CAuthResult),
+        ResumeResult(S2CResumeResult),
+        ResumeToken(S2CResumeToken),
+        ConnectToUser(ConnectToUser),
+        ConnectToUserResult(S2CConnectToUserReply),
+        ConnectCancelled(S2CConnectCancelled),
+        ConnectHandledElsewhere(S2CConnectHandledElsewhere),
+        Signal(S2CSignal),
+        RelayFrame(S2CRelayFrame),
+        SendMailResult(S2CSendMailReply),
+        Mail(S2CMail),
+        Presence(S2CPresence),
+        SetBlockedResult(S2CSetBlockedReply),
+        BlockList(S2CBlockList),
+        SetContactResult(S2CSetContactReply),
+        Contacts(S2CContacts),
+        ContactRequestResult(S2CContactRequestReply),
+        ContactRequest(S2CContactRequest),
+    }
+}
+
+/// First message of a connection, servie answers with `S2CHello` if it speaks `version`
+#[derive(Sirius, Debug)]
+pub struct C2SHello {
+    pub version: u16,
+}
+
+#[derive(Sirius, Debug)]
+pub struct S2CHello {
+    pub version: u16,
 }
 
 #[derive(Sirius, Debug)]
 pub struct C2SAck {
     pub username: legos::ShortIdStr,
+    /// Only one session per device, a user can be online from several devices
+    pub device: DeviceId,
+    /// Last registrie commit id known to the client, eg. from `R2CRegister`,
+    /// hints servie that its mirror might be stale
+    pub commit_hint: Option<legos::CommitId>,
+    /// Appear offline to presence subscribers from the start, see `C2SSetPresence`
+    pub hide_presence: bool,
 }
 
 #[derive(Sirius, Debug)]
@@ -35,7 +172,8 @@ pub struct S2CAuthReq {
 
 #[derive(Sirius, Debug)]
 pub struct C2SAuthRes {
-    pub signed_random: [u8; AUTH_SIZE],
+    /// ML-DSA-87 signature of `S2CAuthReq::random` under `AUTH_CONTEXT`
+    pub signature: legos::MlDsaSignature,
 }
 
 #[derive(Sirius, Debug)]
@@ -44,22 +182,386 @@ pub enum S2CAuthResult {
     Failure,
 }
 
+/// Secret letting a device take its session back after losing the connection
+pub type ResumeToken = [u8; 32];
+
+/// Sent by servie after every successful login or resumption, the token is valid once,
+/// for `grace_secs` after the connection is lost
+///
+/// Meanwhile servie keeps the device online, its pending requests and the messages
+/// meant for it
 #[derive(Sirius, Debug)]
-pub struct ConnectToUser {
-    // TODO: Send WebRTC offer
-    // Issue URL: https://github.com/Colabie/Colabie/issues/73
+pub struct S2CResumeToken {
+    pub token: ResumeToken,
+    pub grace_secs: u32,
+}
+
+/// Sent instead of `C2SAck` to take back a session without authenticating again
+///
+/// Servie answers with `S2CResumeResult`, and expects a `C2SAck` after a `Failure`
+#[derive(Sirius, Debug)]
+pub struct C2SResume {
     pub username: legos::ShortIdStr,
+    pub device: DeviceId,
+    pub token: ResumeToken,
 }
 
 #[derive(Sirius, Debug)]
+pub enum S2CResumeResult {
+    /// Pending requests are pushed again, and queued messages follow
+    Success,
+    /// The token is unknown, already used or expired
+    Failure,
+}
+
+/// Machine readable reason for servie closing the connection
+#[derive(Sirius, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum S2CErrorCode {
+    UnknownUser,
+    AlreadyOnline,
+    ProtocolViolation,
+    /// Never sent as an `S2CError`, a failed login is answered with `S2CAuthResult::Failure`
+    AuthFailed,
+    RateLimited,
+    Internal,
+    IncompatibleVersion,
+}
+
+/// Sent by servie right before it closes the connection because of an error
+#[derive(Sirius, Debug)]
+pub struct S2CError {
+    pub code: S2CErrorCode,
+    pub reason: String,
+}
+
+/// Sent by a clientie to request connecting to `username`,
+/// and pushed by servie to every device of the requested user
+///
+/// The first device to answer settles the request, the others get `S2CConnectHandledElsewhere`
+///
+/// Once accepted, the two peers set up WebRTC with `Signal`s
+#[derive(Sirius, Debug)]
+pub struct ConnectToUser {
+    pub id: RequestId,
+    pub username: legos::ShortIdStr,
+}
+
+#[derive(Sirius, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum S2CConnectToUserResult {
     UserBusy,
     Reject,
     Accept,
+    /// The user didn't answer in time
+    Timeout,
+    /// Withdrawn with `C2SCancelConnect`
+    Cancelled,
+    /// The user isn't online, `C2SSendMail` still reaches them
+    Offline,
+    /// The user's devices have too many messages queued to take the request
+    MailboxFull,
 }
 
+/// Answers the clientie's `ConnectToUser` with the same `id`
 #[derive(Sirius, Debug)]
+pub struct S2CConnectToUserReply {
+    pub id: RequestId,
+    pub result: S2CConnectToUserResult,
+}
+
+#[derive(Sirius, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum C2SConnectToUserResult {
     Reject,
     Accept,
 }
+
+/// Answers servie's `ConnectToUser` with the same `id`
+#[derive(Sirius, Debug)]
+pub struct C2SConnectToUserReply {
+    pub id: RequestId,
+    pub result: C2SConnectToUserResult,
+}
+
+/// Withdraws the clientie's pending `ConnectToUser` with the same `id`
+#[derive(Sirius, Debug)]
+pub struct C2SCancelConnect {
+    pub id: RequestId,
+}
+
+/// Tells the clientie that the `ConnectToUser` pushed with the same `id` was withdrawn,
+/// answering it has no effect anymore
+#[derive(Sirius, Debug)]
+pub struct S2CConnectCancelled {
+    pub id: RequestId,
+    /// Who withdrew the request
+    pub username: legos::ShortIdStr,
+}
+
+/// Tells the clientie that another device of the user answered the `ConnectToUser`
+/// from `username` first, or won the race to accept it
+///
+/// Any connection this device accepted with `username` is void
+#[derive(Sirius, Debug)]
+pub struct S2CConnectHandledElsewhere {
+    pub username: legos::ShortIdStr,
+}
+
+/// Largest SDP blob or ICE candidate servie relays
+pub const MAX_SIGNAL_SIZE: usize = 16 * 1024;
+
+/// WebRTC signaling payload, opaque to servie
+#[derive(Sirius, Debug, Clone)]
+pub enum Signal {
+    /// SDP offer, from the peer setting up the connection
+    Offer(legos::BoundedBytes<1, MAX_SIGNAL_SIZE>),
+    /// SDP answer to an `Offer`
+    Answer(legos::BoundedBytes<1, MAX_SIGNAL_SIZE>),
+    /// Trickled ICE candidate, JSON encoded `RTCIceCandidateInit`
+    IceCandidate(legos::BoundedBytes<1, MAX_SIGNAL_SIZE>),
+}
+
+/// Relayed by servie to `to`, only if the two users accepted to connect
+#[derive(Sirius, Debug)]
+pub struct C2SSignal {
+    pub to: legos::ShortIdStr,
+    pub signal: Signal,
+}
+
+#[derive(Sirius, Debug)]
+pub struct S2CSignal {
+    pub from: legos::ShortIdStr,
+    pub signal: Signal,
+}
+
+/// Largest application frame servie relays
+pub const MAX_RELAY_FRAME_SIZE: usize = 64 * 1024;
+
+/// Application frame relayed by servie when the peers can't reach each other directly
+///
+/// `frame` is end-to-end encrypted by the peers, servie only forwards it
+#[derive(Sirius, Debug)]
+pub struct C2SRelayFrame {
+    pub to: legos::ShortIdStr,
+    pub frame: legos::BoundedBytes<1, MAX_RELAY_FRAME_SIZE>,
+}
+
+#[derive(Sirius, Debug)]
+pub struct S2CRelayFrame {
+    pub from: legos::ShortIdStr,
+    pub frame: legos::BoundedBytes<1, MAX_RELAY_FRAME_SIZE>,
+}
+
+/// Identifies a stored mail of a recipient
+pub type MailId = u64;
+
+/// Largest mail servie stores
+pub const MAX_MAIL_SIZE: usize = 64 * 1024;
+
+/// End-to-end encrypted mail body, servie only stores and forwards it
+pub type MailEnvelope = legos::BoundedBytes<1, MAX_MAIL_SIZE>;
+
+/// Leaves a mail for `to`, delivered with `S2CMail` once they are online
+#[derive(Sirius, Debug)]
+pub struct C2SSendMail {
+    pub id: RequestId,
+    pub to: legos::ShortIdStr,
+    pub envelope: MailEnvelope,
+}
+
+#[derive(Sirius, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum S2CSendMailResult {
+    Stored,
+    /// `to` is not a registered user, or registered too recently for servie to know yet
+    UnknownUser,
+    /// `to` has too much undelivered mail, in total or from the sender
+    MailboxFull,
+}
+
+/// Answers the clientie's `C2SSendMail` with the same `id`
+#[derive(Sirius, Debug)]
+pub struct S2CSendMailReply {
+    pub id: RequestId,
+    pub result: S2CSendMailResult,
+}
+
+/// A stored mail, redelivered on every login until acknowledged with `C2SMailAck`
+#[derive(Sirius, Debug)]
+pub struct S2CMail {
+    pub id: MailId,
+    pub from: legos::ShortIdStr,
+    /// Unix timestamp in seconds servie stored the mail at
+    pub sent_at: u64,
+    pub envelope: MailEnvelope,
+}
+
+#[derive(Sirius, Debug)]
+pub struct C2SMailAck {
+    pub id: MailId,
+}
+
+/// Most users a clientie can subscribe to the presence of at once
+pub const MAX_PRESENCE_SUBSCRIPTIONS: usize = 256;
+
+/// Replaces the users the clientie gets `S2CPresence` about,
+/// at most `MAX_PRESENCE_SUBSCRIPTIONS` of them
+///
+/// Servie answers with the current presence of every subscribed user
+#[derive(Sirius, Debug)]
+pub struct C2SSubscribePresence {
+    pub usernames: legos::BoundedVec<legos::ShortIdStr, MAX_PRESENCE_SUBSCRIPTIONS>,
+}
+
+/// Pushed when a subscribed user comes online or goes offline
+///
+/// Users hiding their presence always appear offline
+#[derive(Sirius, Debug)]
+pub struct S2CPresence {
+    pub username: legos::ShortIdStr,
+    pub online: bool,
+}
+
+/// Hides the clientie's presence from subscribers, or shows it again
+#[derive(Sirius, Debug)]
+pub struct C2SSetPresence {
+    pub hidden: bool,
+}
+
+/// Most users a user can block
+pub const MAX_BLOCKED_USERS: usize = 1024;
+
+/// Blocks `username`, or unblocks them if `blocked` is `false`
+///
+/// Blocked users see the user offline, in `S2CPresence` and when their connection requests
+/// are answered, and their mail is dropped as if it was stored
+#[derive(Sirius, Debug)]
+pub struct C2SSetBlocked {
+    pub id: RequestId,
+    pub username: legos::ShortIdStr,
+    pub blocked: bool,
+}
+
+#[derive(Sirius, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum S2CSetBlockedResult {
+    Done,
+    /// The user already blocks `MAX_BLOCKED_USERS` users
+    ListFull,
+}
+
+/// Answers the clientie's `C2SSetBlocked` with the same `id`
+#[derive(Sirius, Debug)]
+pub struct S2CSetBlockedReply {
+    pub id: RequestId,
+    pub result: S2CSetBlockedResult,
+}
+
+/// Asks for the users the user blocks, answered with `S2CBlockList`
+#[derive(Sirius, Debug)]
+pub struct C2SGetBlockList {
+    pub id: RequestId,
+}
+
+/// Answers the clientie's `C2SGetBlockList` with the same `id`
+#[derive(Sirius, Debug)]
+pub struct S2CBlockList {
+    pub id: RequestId,
+    pub usernames: legos::BoundedVec<legos::ShortIdStr, MAX_BLOCKED_USERS>,
+}
+
+/// Most contacts a user can have
+pub const MAX_CONTACTS: usize = 1024;
+
+/// Only takes connection requests from contacts, or from everyone again if `enabled` is `false`
+///
+/// Anyone else sees the user offline, in `S2CPresence` and when their connection requests
+/// are answered, `C2SContactRequest` still reaches the user
+#[derive(Sirius, Debug)]
+pub struct C2SSetContactsOnly {
+    pub enabled: bool,
+}
+
+/// Adds `username` to the contacts, or removes them if `contact` is `false`
+#[derive(Sirius, Debug)]
+pub struct C2SSetContact {
+    pub id: RequestId,
+    pub username: legos::ShortIdStr,
+    pub contact: bool,
+}
+
+#[derive(Sirius, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum S2CSetContactResult {
+    Done,
+    /// The user already has `MAX_CONTACTS` contacts
+    ListFull,
+}
+
+/// Answers the clientie's `C2SSetContact` with the same `id`
+#[derive(Sirius, Debug)]
+pub struct S2CSetContactReply {
+    pub id: RequestId,
+    pub result: S2CSetContactResult,
+}
+
+/// Asks for the user's contacts, answered with `S2CContacts`
+#[derive(Sirius, Debug)]
+pub struct C2SGetContacts {
+    pub id: RequestId,
+}
+
+/// Answers the clientie's `C2SGetContacts` with the same `id`
+#[derive(Sirius, Debug)]
+pub struct S2CContacts {
+    pub id: RequestId,
+    /// See `C2SSetContactsOnly`
+    pub contacts_only: bool,
+    pub usernames: legos::BoundedVec<legos::ShortIdStr, MAX_CONTACTS>,
+}
+
+/// Asks `username` to add the user to their contacts, pushed to them as `S2CContactRequest`
+/// right away or once they log in
+///
+/// Accepting is up to the other clientie, with `C2SSetContact`
+#[derive(Sirius, Debug)]
+pub struct C2SContactRequest {
+    pub id: RequestId,
+    pub username: legos::ShortIdStr,
+}
+
+#[derive(Sirius, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum S2CContactRequestResult {
+    /// Also the answer when the user blocks the sender or isn't registered, and the request
+    /// is dropped, so whether the user is online never shows
+    Sent,
+    /// Too many contact requests lately, servie limits them separately from other requests
+    RateLimited,
+}
+
+/// Answers the clientie's `C2SContactRequest` with the same `id`
+#[derive(Sirius, Debug)]
+pub struct S2CContactRequestReply {
+    pub id: RequestId,
+    pub result: S2CContactRequestResult,
+}
+
+/// `username` asks to be added to the contacts
+///
+/// Pushed again on every login until answered with `C2SSetContact`, or `C2SSetBlocked`
+#[derive(Sirius, Debug)]
+pub struct S2CContactRequest {
+    pub username: legos::ShortIdStr,
+}
+
+#[test]
+fn envelope_roundtrip() {
+    let msg = C2S::from(ConnectToUser {
+        id: 7,
+        username: legos::ShortIdStr::new("duskyelf").unwrap(),
+    });
+
+    let serialized = msg.serialize_buffered();
+    let (deserialized, len) = C2S::deserialize(&serialized).unwrap();
+
+    assert_eq!(len, serialized.len());
+    assert!(matches!(
+        deserialized,
+        C2S::ConnectToUser(ConnectToUser { id: 7, username }) if *username == "duskyelf"
+    ));
+}
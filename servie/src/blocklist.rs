@@ -0,0 +1,46 @@
+use crate::UserList;
+
+use schemou::{legos::ShortIdStr, MAX_BLOCKED_USERS};
+
+use std::io;
+
+/// Who each user blocks from sending them connection requests, see `schemou::C2SSetBlocked`
+#[derive(Clone)]
+pub struct Blocklist(UserList);
+
+impl Blocklist {
+    // This function blocks on io operations
+    // That's fine as it's called once at the very start
+    pub fn open_or_create() -> io::Result<Self> {
+        let path =
+            std::env::var("BLOCKLIST_PATH").expect("BLOCKLIST_PATH environment variable not set");
+        Self::open(&path)
+    }
+
+    // This function blocks on io operations
+    // That's fine as it's called once at the very start
+    pub fn open(path: &str) -> io::Result<Self> {
+        UserList::open(path, MAX_BLOCKED_USERS).map(Self)
+    }
+
+    /// Users `user` blocks, sorted
+    pub async fn blocked(&self, user: &ShortIdStr) -> io::Result<Vec<ShortIdStr>> {
+        self.0.members(user).await
+    }
+
+    /// Whether `user` blocks `other`
+    pub async fn is_blocked(&self, user: &ShortIdStr, other: &ShortIdStr) -> io::Result<bool> {
+        self.0.contains(user, other).await
+    }
+
+    /// Makes `user` block `other`, or unblock them if `blocked` is `false`,
+    /// returns `false` if blocking would take `user` over `MAX_BLOCKED_USERS`
+    pub async fn set_blocked(
+        &self,
+        user: &ShortIdStr,
+        other: ShortIdStr,
+        blocked: bool,
+    ) -> io::Result<bool> {
+        self.0.set(user, other, blocked).await
+    }
+}
@@ -0,0 +1,247 @@
+use crate::{user_path, UserList};
+
+use schemou::{legos::ShortIdStr, MAX_CONTACTS};
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{sync::Mutex, task::spawn_blocking, time::Instant};
+
+/// Contact requests a user can send within `CONTACT_REQUEST_WINDOW`, from all their devices
+pub const CONTACT_REQUEST_LIMIT: usize = 10;
+
+pub const CONTACT_REQUEST_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Contact requests a user can have waiting for an answer, later ones are dropped
+pub const MAX_PENDING_CONTACT_REQUESTS: usize = 64;
+
+/// Users' contacts, and whether they only take connection requests from them
+///
+/// Contact requests reach users in contacts-only mode too, so they are rate limited
+#[derive(Clone)]
+pub struct Contacts {
+    list: UserList,
+    /// Who asked each user to become a contact, until the user answers
+    pending: UserList,
+    /// Holds an empty file for every user in contacts-only mode
+    only: Arc<Path>,
+    /// When each user sent their recent contact requests, oldest first
+    requests: Arc<Mutex<HashMap<ShortIdStr, VecDeque<Instant>>>>,
+}
+
+impl Contacts {
+    // This function blocks on io operations
+    // That's fine as it's called once at the very start
+    pub fn open_or_create() -> io::Result<Self> {
+        let path =
+            std::env::var("CONTACTS_PATH").expect("CONTACTS_PATH environment variable not set");
+        Self::open(&path)
+    }
+
+    // This function blocks on io operations
+    // That's fine as it's called once at the very start
+    pub fn open(path: &str) -> io::Result<Self> {
+        let root = Path::new(path);
+        let only = root.join("contacts_only");
+        fs::create_dir_all(&only)?;
+
+        Ok(Self {
+            list: UserList::open(root.join("lists"), MAX_CONTACTS)?,
+            pending: UserList::open(root.join("pending"), MAX_PENDING_CONTACT_REQUESTS)?,
+            only: only.into(),
+            requests: Default::default(),
+        })
+    }
+
+    /// `user`'s contacts, sorted
+    pub async fn contacts(&self, user: &ShortIdStr) -> io::Result<Vec<ShortIdStr>> {
+        self.list.members(user).await
+    }
+
+    /// Adds `other` to `user`'s contacts, or removes them if `contact` is `false`,
+    /// returns `false` if adding would take `user` over `MAX_CONTACTS`
+    ///
+    /// Either way answers `other`'s contact request
+    pub async fn set_contact(
+        &self,
+        user: &ShortIdStr,
+        other: ShortIdStr,
+        contact: bool,
+    ) -> io::Result<bool> {
+        if !self.list.set(user, other.clone(), contact).await? {
+            return Ok(false);
+        }
+        self.dismiss_request(user, other).await?;
+        Ok(true)
+    }
+
+    /// Users whose contact requests `user` didn't answer yet, sorted
+    pub async fn pending_requests(&self, user: &ShortIdStr) -> io::Result<Vec<ShortIdStr>> {
+        self.pending.members(user).await
+    }
+
+    /// Keeps `from`'s contact request until `user` answers it, dropped if `user` has
+    /// `MAX_PENDING_CONTACT_REQUESTS` waiting already
+    pub async fn store_request(&self, user: &ShortIdStr, from: ShortIdStr) -> io::Result<()> {
+        self.pending.set(user, from, true).await?;
+        Ok(())
+    }
+
+    /// Forgets `from`'s contact request to `user`, if any
+    pub async fn dismiss_request(&self, user: &ShortIdStr, from: ShortIdStr) -> io::Result<()> {
+        self.pending.set(user, from, false).await?;
+        Ok(())
+    }
+
+    pub async fn is_contacts_only(&self, user: &ShortIdStr) -> io::Result<bool> {
+        let path = user_path(&self.only, user);
+        spawn_blocking(move || path.try_exists()).await.unwrap()
+    }
+
+    pub async fn set_contacts_only(&self, user: &ShortIdStr, enabled: bool) -> io::Result<()> {
+        let path = user_path(&self.only, user);
+        spawn_blocking(move || match enabled {
+            true => fs::write(path, []),
+            false => match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            },
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Whether `user` takes connection requests from `from`
+    pub async fn accepts(&self, user: &ShortIdStr, from: &ShortIdStr) -> io::Result<bool> {
+        Ok(!self.is_contacts_only(user).await? || self.list.contains(user, from).await?)
+    }
+
+    /// Counts a contact request of `user`, returns `false` if they are over
+    /// `CONTACT_REQUEST_LIMIT`
+    pub async fn charge_request(&self, user: &ShortIdStr) -> bool {
+        self.charge_request_at(user, Instant::now()).await
+    }
+
+    async fn charge_request_at(&self, user: &ShortIdStr, now: Instant) -> bool {
+        let mut requests = self.requests.lock().await;
+
+        // Forget the users whose requests all left the window
+        requests.retain(|_, sent| {
+            while sent
+                .front()
+                .is_some_and(|sent| now.duration_since(*sent) >= CONTACT_REQUEST_WINDOW)
+            {
+                sent.pop_front();
+            }
+            !sent.is_empty()
+        });
+
+        let sent = requests.entry(user.clone()).or_default();
+        if sent.len() >= CONTACT_REQUEST_LIMIT {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod contacts_tests {
+    use super::{Contacts, CONTACT_REQUEST_LIMIT, CONTACT_REQUEST_WINDOW};
+
+    use schemou::legos::ShortIdStr;
+
+    use std::fs;
+
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn contacts_only() {
+        let path = rand::random::<u64>().to_string();
+        let contacts = Contacts::open(&path).unwrap();
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        // Everyone is accepted until the user opts in
+        assert!(contacts.accepts(&duskyelf, &thatmagicalcat).await.unwrap());
+
+        contacts.set_contacts_only(&duskyelf, true).await.unwrap();
+        assert!(!contacts.accepts(&duskyelf, &thatmagicalcat).await.unwrap());
+
+        assert!(contacts
+            .set_contact(&duskyelf, thatmagicalcat.clone(), true)
+            .await
+            .unwrap());
+        assert!(contacts.accepts(&duskyelf, &thatmagicalcat).await.unwrap());
+
+        // The setting survives reopening
+        let contacts = Contacts::open(&path).unwrap();
+        assert!(contacts.is_contacts_only(&duskyelf).await.unwrap());
+        contacts.set_contacts_only(&duskyelf, false).await.unwrap();
+        assert!(!contacts.is_contacts_only(&duskyelf).await.unwrap());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn request_limit() {
+        let path = rand::random::<u64>().to_string();
+        let contacts = Contacts::open(&path).unwrap();
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        for _ in 0..CONTACT_REQUEST_LIMIT {
+            assert!(contacts.charge_request(&duskyelf).await);
+        }
+        assert!(!contacts.charge_request(&duskyelf).await);
+
+        // Limits are per user, and requests leave the window
+        assert!(contacts.charge_request(&thatmagicalcat).await);
+        let later = Instant::now() + CONTACT_REQUEST_WINDOW;
+        assert!(contacts.charge_request_at(&duskyelf, later).await);
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pending_requests() {
+        let path = rand::random::<u64>().to_string();
+        let contacts = Contacts::open(&path).unwrap();
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let ferris = ShortIdStr::new("ferris").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        for from in [&thatmagicalcat, &ferris, &thatmagicalcat] {
+            contacts
+                .store_request(&duskyelf, from.clone())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            contacts.pending_requests(&duskyelf).await.unwrap(),
+            [ferris.clone(), thatmagicalcat.clone()]
+        );
+
+        // Answering either way settles the request
+        assert!(contacts
+            .set_contact(&duskyelf, ferris, false)
+            .await
+            .unwrap());
+        contacts
+            .dismiss_request(&duskyelf, thatmagicalcat)
+            .await
+            .unwrap();
+        assert!(contacts
+            .pending_requests(&duskyelf)
+            .await
+            .unwrap()
+            .is_empty());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+}
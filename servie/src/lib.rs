@@ -1,17 +1,73 @@
+pub mod blocklist;
+pub mod contacts;
+pub mod mailbox;
 pub mod mirror;
-
+pub mod queue;
+pub mod relay;
+pub mod resume;
+pub mod session;
+pub mod userlist;
+
+#[cfg(test)]
+mod test_utils;
+
+pub use blocklist::Blocklist;
+pub use contacts::Contacts;
+pub use mailbox::Mailbox;
 pub use mirror::Mirror;
+pub use queue::{Overflow, QueueConfig};
+pub use relay::RelayQuota;
+pub use resume::Resumptions;
+pub use userlist::UserList;
+
+use schemou::legos::{self, ShortIdStr};
+use schemou::{
+    DeviceId, S2CError, S2CErrorCode, Sirius, MAX_RELAY_FRAME_SIZE, PROTOCOL_VERSION, S2C,
+};
 
-use schemou::legos::ShortIdStr;
-use schemou::Sirius;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use std::{collections::HashMap, error::Error, fmt, sync::Arc, time::Duration};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use tokio::{sync::RwLock, time::Instant};
+
+/// Everything a connection needs, shared between all connections
+#[derive(Clone)]
+pub struct AppState {
+    pub mirror: Mirror,
+    pub user_channels: UserChannels,
+    pub mailbox: Mailbox,
+    pub blocklist: Blocklist,
+    pub contacts: Contacts,
+    pub resumptions: Resumptions,
+    pub relay: RelayQuota,
+    pub heartbeat: Heartbeat,
+}
 
-use axum::extract::ws::{Message, WebSocket};
-use tokio::{
-    sync::{mpsc, RwLock},
-    time::{timeout_at, Instant},
-};
+/// How often servie pings a connection, and how long a connection may stay silent
+/// before it's considered dead and reaped
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(45),
+        }
+    }
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum ServieError {
@@ -21,11 +77,105 @@ pub enum ServieError {
     #[error("Axum error: {0}")]
     AxumError(#[from] axum::Error),
 
-    #[error("Serialization error: {0}")]
-    SerializationError(#[from] schemou::SiriusError),
+    #[error("Deserialization error: {0}")]
+    DeserializationError(#[from] schemou::DecodeError),
 
     #[error("User doesn't comply with protocol: {0}")]
     NonCompliance(&'static str),
+
+    #[error("Username is not registered")]
+    UnknownUser,
+
+    #[error("User is already online from this device")]
+    AlreadyOnline,
+
+    #[error("User is online from the maximum of {MAX_DEVICES} devices")]
+    TooManyDevices,
+
+    #[error("User failed to authenticate")]
+    AuthFailed,
+
+    #[error("Incompatible protocol version {0}, servie speaks {PROTOCOL_VERSION}")]
+    IncompatibleVersion(u16),
+
+    #[error("Relay quota of {0} bytes exceeded")]
+    RelayQuotaExceeded(u64),
+
+    #[error("Storage error: {0}")]
+    StorageError(#[from] std::io::Error),
+
+    #[error("Connection silent for {0:?}, considered dead")]
+    Unresponsive(Duration),
+
+    #[error("Connection taken over by the same device reconnecting")]
+    TakenOver,
+}
+
+impl ServieError {
+    /// Error code reported to the client, `None` if the client can't be told anymore
+    pub fn code(&self) -> Option<S2CErrorCode> {
+        match self {
+            ServieError::SocketClosed | ServieError::Unresponsive(_) | ServieError::TakenOver => {
+                None
+            }
+            ServieError::AxumError(_) | ServieError::StorageError(_) => {
+                Some(S2CErrorCode::Internal)
+            }
+            ServieError::DeserializationError(_) | ServieError::NonCompliance(_) => {
+                Some(S2CErrorCode::ProtocolViolation)
+            }
+            ServieError::UnknownUser => Some(S2CErrorCode::UnknownUser),
+            ServieError::AlreadyOnline | ServieError::TooManyDevices => {
+                Some(S2CErrorCode::AlreadyOnline)
+            }
+            ServieError::AuthFailed => Some(S2CErrorCode::AuthFailed),
+            ServieError::IncompatibleVersion(_) => Some(S2CErrorCode::IncompatibleVersion),
+            ServieError::RelayQuotaExceeded(_) => Some(S2CErrorCode::RateLimited),
+        }
+    }
+
+    /// Tells the client about the error with an `S2CError` and closes the socket
+    pub async fn report(&self, socket: &mut LiveSocket) {
+        let Some(code) = self.code() else {
+            return;
+        };
+
+        // Internal details stay in the logs
+        let reason = match code {
+            S2CErrorCode::Internal => "Internal server error".to_string(),
+            _ => self.to_string(),
+        };
+
+        // A failed login was already answered with `S2CAuthResult::Failure`
+        if !matches!(self, ServieError::AuthFailed) {
+            _ = socket.send_se(S2C::Error(S2CError { code, reason })).await;
+        }
+        _ = socket
+            .ws
+            .send(Message::Close(Some(CloseFrame {
+                code: close_code(code),
+                reason: format!("{code:?}").into(),
+            })))
+            .await;
+    }
+}
+
+/// WebSocket close code (RFC 6455) servie closes with for an error code
+pub fn close_code(code: S2CErrorCode) -> u16 {
+    match code {
+        S2CErrorCode::ProtocolViolation | S2CErrorCode::IncompatibleVersion => 1002,
+        S2CErrorCode::UnknownUser | S2CErrorCode::AlreadyOnline | S2CErrorCode::AuthFailed => 1008,
+        S2CErrorCode::Internal => 1011,
+        S2CErrorCode::RateLimited => 1013,
+    }
+}
+
+/// Where `user`'s file or directory goes under `root`
+///
+/// `ShortIdStr` only allows lowercase alphanumerics, '.' and '_', and is at least
+/// 3 characters long, so it can't escape `root`
+pub fn user_path(root: &Path, user: &ShortIdStr) -> PathBuf {
+    root.join(user.as_str())
 }
 
 pub type Result<T, E = ServieError> = std::result::Result<T, E>;
@@ -34,15 +184,38 @@ pub type Result<T, E = ServieError> = std::result::Result<T, E>;
 pub trait SerdeSocket {
     async fn recv_de<T: Sirius + fmt::Debug>(&mut self) -> Result<T>;
     async fn send_se<T: Sirius + fmt::Debug>(&mut self, data: T) -> Result<()>;
+
+    /// Asks the peer for a sign of life
+    async fn ping(&mut self) -> Result<()>;
+
+    /// When the peer last sent anything, pongs included
+    fn last_seen(&self) -> Instant;
+}
+
+/// A `WebSocket` that keeps track of when the peer was last heard from
+pub struct LiveSocket {
+    ws: WebSocket,
+    last_seen: Instant,
+}
+
+impl LiveSocket {
+    pub fn new(ws: WebSocket) -> Self {
+        Self {
+            ws,
+            last_seen: Instant::now(),
+        }
+    }
 }
 
-impl SerdeSocket for WebSocket {
+impl SerdeSocket for LiveSocket {
     async fn recv_de<T: Sirius + fmt::Debug>(&mut self) -> Result<T> {
         loop {
             let msg = self
+                .ws
                 .recv()
                 .await
                 .ok_or_else(|| ServieError::SocketClosed)??;
+            self.last_seen = Instant::now();
 
             let data = match msg {
                 Message::Binary(msg) => {
@@ -69,7 +242,7 @@ impl SerdeSocket for WebSocket {
                 }
             };
 
-            let (deserialized, _) = T::deserialize(&data)?;
+            let deserialized = schemou::decode::<T>(&data)?;
             tracing::trace!("Deserialized message: {:?}", deserialized);
             return Ok(deserialized);
         }
@@ -79,10 +252,29 @@ impl SerdeSocket for WebSocket {
         tracing::trace!("Sending message: {:?}", data);
 
         let serialized = data.serialize_buffered();
-        self.send(serialized.into()).await?;
+        self.ws.send(serialized.into()).await?;
 
         Ok(())
     }
+
+    async fn ping(&mut self) -> Result<()> {
+        self.ws.send(Message::Ping(Default::default())).await?;
+        Ok(())
+    }
+
+    fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+}
+
+/// Devices a user can be logged in from at once
+pub const MAX_DEVICES: usize = 8;
+
+/// A logged in device of a user, what channel messages are addressed to
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DeviceAddr {
+    pub username: ShortIdStr,
+    pub device: DeviceId,
 }
 
 #[derive(Default)]
@@ -91,35 +283,64 @@ pub struct SelfChannel {
 }
 
 struct SelfChannelInner {
-    username: ShortIdStr,
-    channel: mpsc::Receiver<ChannelMsgWithSender>,
+    addr: DeviceAddr,
+    channel: queue::Receiver<ChannelMsgWithSender>,
     channels: UserChannels,
+    /// Users told `ChannelMsg::ConnectToUser` that didn't settle yet, cancelled on drop
+    requested: HashSet<ShortIdStr>,
+    /// Users whose presence we are told about with `ChannelMsg::Presence`
+    subscribed: HashSet<ShortIdStr>,
 }
 
 pub struct ChannelMsgWithSender {
-    pub from: ShortIdStr,
+    pub from: DeviceAddr,
     pub message: ChannelMsg,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ChannelMsg {
+    /// Sent to every device of the requested user
     ConnectToUser,
 
     UserBusy,
     ConnectToUserReject,
     ConnectToUserAccept,
+    /// The sender withdrew its `ConnectToUser`
+    ConnectToUserCancel,
+    /// Another device of ours answered the sender's `ConnectToUser` first
+    ConnectHandledElsewhere,
+
+    /// WebRTC signaling, relayed to the client if the sender is an accepted peer
+    Signal(schemou::Signal),
+
+    /// Opaque application frame, relayed to the client if the sender is an accepted peer
+    RelayFrame(legos::BoundedBytes<1, MAX_RELAY_FRAME_SIZE>),
+
+    /// The sender left mail for us
+    MailArrived,
+
+    /// The sender asks to be added to our contacts
+    ContactRequest,
+
+    /// The sender, one of our presence subscriptions, came online or went offline
+    Presence {
+        online: bool,
+    },
 }
 
 impl SelfChannel {
-    pub async fn new(username: ShortIdStr, channels: UserChannels) -> Self {
-        let (tx, rx) = mpsc::channel(1);
-        channels.add(username.clone(), tx).await;
+    /// Goes online, telling our presence subscribers unless `hidden`
+    pub async fn new(addr: DeviceAddr, channels: UserChannels, hidden: bool) -> Self {
+        let (rx, watchers) = channels.add(addr.clone(), hidden).await;
+        announce(&addr, &watchers, true);
 
         Self {
             i: Some(SelfChannelInner {
-                username,
+                addr,
                 channel: rx,
                 channels,
+                requested: HashSet::new(),
+                subscribed: HashSet::new(),
             }),
         }
     }
@@ -127,31 +348,118 @@ impl SelfChannel {
     pub async fn hear(&mut self) -> ChannelMsgWithSender {
         let this = self.i.as_mut().expect("SelfChannel is dropped");
 
-        this.channel
-            .recv()
-            .await
-            .expect("unreachable: a sender should always be present in the users_channels map")
+        this.channel.recv().await
     }
 
-    pub async fn listen(&mut self, to: &ShortIdStr) -> Option<ChannelMsg> {
+    /// Tells every device of `other` that we want to connect to `to`,
+    /// fails if none of them could be told
+    ///
+    /// Until `settle` is called, `to` gets a `ChannelMsg::ConnectToUserCancel` if we drop
+    pub async fn request_connect(
+        &mut self,
+        to: &ShortIdStr,
+        other: &UserChannel,
+    ) -> Result<(), queue::SendError> {
         let this = self.i.as_mut().expect("SelfChannel is dropped");
 
-        let assume_ignored = Instant::now() + Duration::from_secs(10);
-        loop {
-            match timeout_at(assume_ignored, this.channel.recv()).await {
-                Ok(Some(msg)) if &msg.from == to => {
-                    return Some(msg.message);
-                }
-                Ok(Some(msg)) => {
-                    if let Some(other) = this.channels.get(&msg.from).await {
-                        let _ = other.try_tell(&this.username, ChannelMsg::UserBusy);
-                    }
-                    continue;
-                }
-                Ok(None) => {
-                    unreachable!("a sender should always be present in the users_channels map")
-                }
-                Err(_) => return None,
+        other
+            .tell_all(&this.addr, ChannelMsg::ConnectToUser)
+            .await?;
+        this.requested.insert(to.clone());
+        Ok(())
+    }
+
+    /// Forgets our request to `to`, as it was answered, expired or cancelled
+    pub fn settle(&mut self, to: &ShortIdStr) {
+        let this = self.i.as_mut().expect("SelfChannel is dropped");
+        this.requested.remove(to);
+    }
+
+    /// Replaces the users we get `ChannelMsg::Presence` about, returns whether each
+    /// of them is online right now
+    ///
+    /// The caller enforces `MAX_PRESENCE_SUBSCRIPTIONS`
+    pub async fn subscribe(&mut self, users: HashSet<ShortIdStr>) -> Vec<(ShortIdStr, bool)> {
+        let this = self.i.as_mut().expect("SelfChannel is dropped");
+
+        let presence = this
+            .channels
+            .resubscribe(&this.addr, &this.subscribed, &users)
+            .await;
+        this.subscribed = users;
+        presence
+    }
+
+    pub fn is_subscribed(&self, user: &ShortIdStr) -> bool {
+        let this = self.i.as_ref().expect("SelfChannel is dropped");
+        this.subscribed.contains(user)
+    }
+
+    /// Tells our presence subscribers, only the devices of `user` if given, whether we are
+    /// online again, as who may see it changed
+    ///
+    /// Nothing changed for them if we appear offline anyway
+    pub async fn reannounce(&self, user: Option<&ShortIdStr>) {
+        let this = self.i.as_ref().expect("SelfChannel is dropped");
+
+        if let Some(watchers) = this.channels.visible_to(&this.addr.username, user).await {
+            announce(&this.addr, &watchers, true);
+        }
+    }
+
+    /// Hides this device's presence from subscribers, or shows it again
+    ///
+    /// The user appears online while any of their devices is online and not hidden
+    pub async fn set_hidden(&mut self, hidden: bool) {
+        let this = self.i.as_mut().expect("SelfChannel is dropped");
+
+        if let Some((watchers, online)) = this.channels.set_hidden(&this.addr, hidden).await {
+            announce(&this.addr, &watchers, online);
+        }
+    }
+}
+
+/// Tells `watchers` that the user of `from` came online or went offline
+///
+/// Presence is best effort, a watcher with a full channel misses the update whatever the
+/// overflow policy
+fn announce(from: &DeviceAddr, watchers: &[DeviceChannel], online: bool) {
+    for watcher in watchers {
+        if watcher
+            .try_tell(from, ChannelMsg::Presence { online })
+            .is_err()
+        {
+            tracing::debug!("failed to deliver a presence update");
+        }
+    }
+}
+
+impl SelfChannel {
+    /// Goes offline like dropping, but returns only once the device is gone from `UserChannels`
+    pub async fn close(mut self) {
+        if let Some(this) = self.i.take() {
+            tracing::debug!("closing user");
+            this.leave().await;
+        }
+    }
+}
+
+impl SelfChannelInner {
+    async fn leave(self) {
+        let overflows = self.channel.overflows();
+        if overflows > 0 {
+            tracing::info!(overflows, "channel overflowed while the device was online");
+        }
+
+        let watchers = self.channels.remove(&self.addr, &self.subscribed).await;
+        announce(&self.addr, &watchers, false);
+
+        // Don't leave the users we asked to connect with a dead request
+        for to in self.requested {
+            if let Some(other) = self.channels.get(&to).await {
+                _ = other
+                    .tell_all(&self.addr, ChannelMsg::ConnectToUserCancel)
+                    .await;
             }
         }
     }
@@ -161,16 +469,26 @@ impl Drop for SelfChannel {
     fn drop(&mut self) {
         if let Some(this) = self.i.take() {
             tracing::debug!("dropping user");
-            tokio::spawn(async move { this.channels.remove(&this.username).await });
+            tokio::spawn(this.leave());
         }
     }
 }
 
-pub struct UserChannel(mpsc::Sender<ChannelMsgWithSender>);
+/// Channel of one logged in device
+#[derive(Clone)]
+pub struct DeviceChannel {
+    device: DeviceId,
+    sender: queue::Sender<ChannelMsgWithSender>,
+}
 
-impl UserChannel {
-    pub async fn tell(&self, from: &ShortIdStr, value: ChannelMsg) -> Result<(), impl Error> {
-        self.0
+impl DeviceChannel {
+    pub fn device(&self) -> DeviceId {
+        self.device
+    }
+
+    /// Tells the device following the overflow policy of `UserChannels`
+    pub async fn tell(&self, from: &DeviceAddr, value: ChannelMsg) -> Result<(), queue::SendError> {
+        self.sender
             .send(ChannelMsgWithSender {
                 from: from.clone(),
                 message: value,
@@ -178,16 +496,122 @@ impl UserChannel {
             .await
     }
 
-    pub fn try_tell(&self, from: &ShortIdStr, value: ChannelMsg) -> Result<(), impl Error> {
-        self.0.try_send(ChannelMsgWithSender {
+    /// Tells the device only if its channel has room right away
+    pub fn try_tell(&self, from: &DeviceAddr, value: ChannelMsg) -> Result<(), queue::SendError> {
+        self.sender.try_send(ChannelMsgWithSender {
             from: from.clone(),
             message: value,
         })
     }
 }
 
+/// Channels of every device a user is logged in from
+pub struct UserChannel(Vec<DeviceChannel>);
+
+impl UserChannel {
+    pub fn device(&self, device: DeviceId) -> Option<&DeviceChannel> {
+        self.0.iter().find(|channel| channel.device == device)
+    }
+
+    /// Tells every device, succeeds if any of them could be told
+    ///
+    /// Otherwise fails with `SendError::Full` if any device's channel was full,
+    /// as the rest went offline meanwhile
+    pub async fn tell_all(
+        &self,
+        from: &DeviceAddr,
+        value: ChannelMsg,
+    ) -> Result<(), queue::SendError> {
+        let mut result = Err(queue::SendError::Closed);
+        for channel in &self.0 {
+            match channel.tell(from, value.clone()).await {
+                Ok(()) => result = Ok(()),
+                Err(queue::SendError::Full) if result.is_err() => {
+                    result = Err(queue::SendError::Full)
+                }
+                Err(_) => {}
+            }
+        }
+        result
+    }
+
+    /// Tells every device but `except`, ignoring the ones that couldn't be told
+    pub async fn tell_others(&self, except: DeviceId, from: &DeviceAddr, value: ChannelMsg) {
+        for channel in self.0.iter().filter(|channel| channel.device != except) {
+            _ = channel.tell(from, value.clone()).await;
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
-pub struct UserChannels(Arc<RwLock<HashMap<ShortIdStr, mpsc::Sender<ChannelMsgWithSender>>>>);
+pub struct UserChannels(Arc<RwLock<Registry>>);
+
+/// Who is online from which devices, and who wants to know about it
+#[derive(Debug, Default)]
+struct Registry {
+    users: HashMap<ShortIdStr, HashMap<DeviceId, Device>>,
+    /// Presence subscribers of a user, by the user they watch, who might be offline
+    watchers: HashMap<ShortIdStr, HashSet<DeviceAddr>>,
+    /// Of every device's channel
+    queue: QueueConfig,
+    /// Messages refused or dropped by any device's full channel, since servie started
+    overflows: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+struct Device {
+    channel: queue::Sender<ChannelMsgWithSender>,
+    /// Hiding its presence
+    hidden: bool,
+}
+
+impl Registry {
+    fn is_visible(&self, username: &ShortIdStr) -> bool {
+        self.users
+            .get(username)
+            .is_some_and(|devices| devices.values().any(|device| !device.hidden))
+    }
+
+    fn channel(&self, addr: &DeviceAddr) -> Option<DeviceChannel> {
+        let device = self.users.get(&addr.username)?.get(&addr.device)?;
+        Some(DeviceChannel {
+            device: addr.device,
+            sender: device.channel.clone(),
+        })
+    }
+
+    fn watchers_of(&self, username: &ShortIdStr) -> Vec<DeviceChannel> {
+        self.watchers
+            .get(username)
+            .into_iter()
+            .flatten()
+            .filter_map(|watcher| self.channel(watcher))
+            .collect()
+    }
+
+    fn unwatch(&mut self, watcher: &DeviceAddr, username: &ShortIdStr) {
+        if let Some(watchers) = self.watchers.get_mut(username) {
+            watchers.remove(watcher);
+            if watchers.is_empty() {
+                self.watchers.remove(username);
+            }
+        }
+    }
+
+    /// Runs `change` on the registry, returns the watchers of `username` to tell
+    /// and whether the user is now visibly online, if the change made a difference
+    fn presence_change(
+        &mut self,
+        username: &ShortIdStr,
+        change: impl FnOnce(&mut Self),
+    ) -> Option<(Vec<DeviceChannel>, bool)> {
+        let was_visible = self.is_visible(username);
+        change(self);
+        let visible = self.is_visible(username);
+
+        (visible != was_visible).then(|| (self.watchers_of(username), visible))
+    }
+}
 
 impl Default for UserChannels {
     fn default() -> Self {
@@ -197,22 +621,239 @@ impl Default for UserChannels {
 
 impl UserChannels {
     pub fn new() -> Self {
-        Self(Arc::new(RwLock::new(HashMap::new())))
+        Self::with_config(QueueConfig::default())
+    }
+
+    /// Device channels are created with the capacity and overflow policy of `queue`
+    pub fn with_config(queue: QueueConfig) -> Self {
+        Self(Arc::new(RwLock::new(Registry {
+            queue,
+            ..Default::default()
+        })))
+    }
+
+    /// Messages refused or dropped by any device's full channel, since servie started
+    pub async fn overflows(&self) -> u64 {
+        self.0.read().await.overflows.load(Ordering::Relaxed)
     }
 
+    /// Every online device of `username`
     pub async fn get(&self, username: &ShortIdStr) -> Option<UserChannel> {
-        self.0.read().await.get(username).cloned().map(UserChannel)
+        let registry = self.0.read().await;
+        let devices = registry.users.get(username)?;
+
+        Some(UserChannel(
+            devices
+                .iter()
+                .map(|(device, channel)| DeviceChannel {
+                    device: *device,
+                    sender: channel.channel.clone(),
+                })
+                .collect(),
+        ))
     }
 
+    pub async fn get_device(&self, addr: &DeviceAddr) -> Option<DeviceChannel> {
+        self.0.read().await.channel(addr)
+    }
+
+    /// Whether `username` is online from any device, even if hiding their presence
     pub async fn is_online(&self, username: &ShortIdStr) -> bool {
-        self.0.read().await.contains_key(username)
+        self.0.read().await.users.contains_key(username)
+    }
+
+    pub async fn is_device_online(&self, addr: &DeviceAddr) -> bool {
+        self.0.read().await.channel(addr).is_some()
+    }
+
+    /// Devices `username` is online from
+    pub async fn device_count(&self, username: &ShortIdStr) -> usize {
+        self.0
+            .read()
+            .await
+            .users
+            .get(username)
+            .map_or(0, HashMap::len)
+    }
+
+    /// Adds an online device, returns its channel's receiving end,
+    /// and the watchers to tell if the user just came online
+    async fn add(
+        &self,
+        addr: DeviceAddr,
+        hidden: bool,
+    ) -> (queue::Receiver<ChannelMsgWithSender>, Vec<DeviceChannel>) {
+        let mut registry = self.0.write().await;
+        let username = addr.username.clone();
+        let (channel, rx) = queue::queue(registry.queue, registry.overflows.clone());
+
+        let watchers = registry
+            .presence_change(&username, |registry| {
+                registry
+                    .users
+                    .entry(addr.username)
+                    .or_default()
+                    .insert(addr.device, Device { channel, hidden });
+            })
+            .map(|(watchers, _)| watchers)
+            .unwrap_or_default();
+        (rx, watchers)
+    }
+
+    /// Removes an online device along with its `subscribed` presence subscriptions,
+    /// returns the watchers to tell if the user just went offline
+    async fn remove(
+        &self,
+        addr: &DeviceAddr,
+        subscribed: &HashSet<ShortIdStr>,
+    ) -> Vec<DeviceChannel> {
+        let mut registry = self.0.write().await;
+        for watched in subscribed {
+            registry.unwatch(addr, watched);
+        }
+
+        registry
+            .presence_change(&addr.username, |registry| {
+                if let Some(devices) = registry.users.get_mut(&addr.username) {
+                    devices.remove(&addr.device);
+                    if devices.is_empty() {
+                        registry.users.remove(&addr.username);
+                    }
+                }
+            })
+            .map(|(watchers, _)| watchers)
+            .unwrap_or_default()
     }
 
-    async fn add(&self, username: ShortIdStr, channel: mpsc::Sender<ChannelMsgWithSender>) {
-        self.0.write().await.insert(username, channel);
+    /// Moves `watcher`'s presence subscriptions from `old` to `new`,
+    /// returns whether each user in `new` is visibly online
+    async fn resubscribe(
+        &self,
+        watcher: &DeviceAddr,
+        old: &HashSet<ShortIdStr>,
+        new: &HashSet<ShortIdStr>,
+    ) -> Vec<(ShortIdStr, bool)> {
+        let mut registry = self.0.write().await;
+        for watched in old.difference(new) {
+            registry.unwatch(watcher, watched);
+        }
+
+        new.iter()
+            .map(|watched| {
+                registry
+                    .watchers
+                    .entry(watched.clone())
+                    .or_default()
+                    .insert(watcher.clone());
+                (watched.clone(), registry.is_visible(watched))
+            })
+            .collect()
     }
 
-    async fn remove(&self, username: &ShortIdStr) {
-        self.0.write().await.remove(username);
+    /// Watchers of `username`, only the devices of `user` if given,
+    /// if `username` is visibly online
+    async fn visible_to(
+        &self,
+        username: &ShortIdStr,
+        user: Option<&ShortIdStr>,
+    ) -> Option<Vec<DeviceChannel>> {
+        let registry = self.0.read().await;
+        if !registry.is_visible(username) {
+            return None;
+        }
+
+        Some(
+            registry
+                .watchers
+                .get(username)
+                .into_iter()
+                .flatten()
+                .filter(|watcher| user.is_none_or(|user| watcher.username == *user))
+                .filter_map(|watcher| registry.channel(watcher))
+                .collect(),
+        )
+    }
+
+    /// Hides or shows an online device, returns the watchers to tell
+    /// and whether the user is now visibly online, if it changed
+    async fn set_hidden(
+        &self,
+        addr: &DeviceAddr,
+        hidden: bool,
+    ) -> Option<(Vec<DeviceChannel>, bool)> {
+        let mut registry = self.0.write().await;
+
+        registry.presence_change(&addr.username, |registry| {
+            if let Some(device) = registry
+                .users
+                .get_mut(&addr.username)
+                .and_then(|devices| devices.get_mut(&addr.device))
+            {
+                device.hidden = hidden;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod channel_tests {
+    use super::{ChannelMsg, DeviceAddr, SelfChannel, UserChannels};
+
+    use schemou::legos::ShortIdStr;
+
+    fn addr(username: &str, device: u64) -> DeviceAddr {
+        DeviceAddr {
+            username: ShortIdStr::new(username).unwrap(),
+            device,
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_on_drop() {
+        let channels = UserChannels::new();
+        let duskyelf = addr("duskyelf", 1);
+        let thatmagicalcat = addr("thatmagicalcat", 1);
+
+        let mut caller = SelfChannel::new(duskyelf.clone(), channels.clone(), false).await;
+        let mut callee = SelfChannel::new(thatmagicalcat.clone(), channels.clone(), false).await;
+
+        let other = channels.get(&thatmagicalcat.username).await.unwrap();
+        caller
+            .request_connect(&thatmagicalcat.username, &other)
+            .await
+            .unwrap();
+        drop(caller);
+
+        let heard = callee.hear().await;
+        assert_eq!(heard.from, duskyelf);
+        assert!(matches!(heard.message, ChannelMsg::ConnectToUser));
+
+        let heard = callee.hear().await;
+        assert_eq!(heard.from, duskyelf);
+        assert!(matches!(heard.message, ChannelMsg::ConnectToUserCancel));
+        assert!(!channels.is_online(&duskyelf.username).await);
+    }
+
+    #[tokio::test]
+    async fn devices() {
+        let channels = UserChannels::new();
+        let laptop = addr("duskyelf", 1);
+        let desktop = addr("duskyelf", 2);
+
+        let first = SelfChannel::new(laptop.clone(), channels.clone(), false).await;
+        let second = SelfChannel::new(desktop.clone(), channels.clone(), false).await;
+        assert_eq!(channels.device_count(&laptop.username).await, 2);
+        assert!(channels.is_device_online(&desktop).await);
+
+        drop(first);
+        while channels.is_device_online(&laptop).await {
+            tokio::task::yield_now().await;
+        }
+        assert!(channels.is_online(&laptop.username).await);
+
+        drop(second);
+        while channels.is_online(&laptop.username).await {
+            tokio::task::yield_now().await;
+        }
     }
 }
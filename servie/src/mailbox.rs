@@ -0,0 +1,407 @@
+use crate::user_path;
+
+use schemou::{legos::ShortIdStr, MailEnvelope, MailId, S2CMail, Sirius};
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::task::spawn_blocking;
+
+/// Mails a recipient can have waiting at once
+pub const MAILBOX_MAX_MAILS: usize = 256;
+
+/// Bytes of mail a recipient can have waiting at once
+pub const MAILBOX_MAX_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Mails a recipient can have waiting from a single sender, so one sender can't fill
+/// the whole mailbox
+pub const MAILBOX_MAX_MAILS_PER_SENDER: usize = MAILBOX_MAX_MAILS / 4;
+
+/// Mails nobody picked up within this are dropped
+pub const MAIL_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Store-and-forward mails for users, one directory per recipient and one file per mail
+///
+/// Files hold the `S2CMail` that delivers them, named after the zero padded hex `MailId`
+/// so that a directory listing sorts them by arrival. They are written under a temporary
+/// name and renamed into place, so readers never see a partial mail
+#[derive(Clone)]
+pub struct Mailbox {
+    root: Arc<Path>,
+    last_id: Arc<AtomicU64>,
+}
+
+impl Mailbox {
+    // This function blocks on io operations
+    // That's fine as it's called once at the very start
+    pub fn open_or_create() -> io::Result<Self> {
+        let path =
+            std::env::var("MAILBOX_PATH").expect("MAILBOX_PATH environment variable not set");
+        Self::open(&path)
+    }
+
+    // This function blocks on io operations
+    // That's fine as it's called once at the very start
+    pub fn open(path: &str) -> io::Result<Self> {
+        fs::create_dir_all(path)?;
+
+        Ok(Self {
+            root: Path::new(path).into(),
+            last_id: Default::default(),
+        })
+    }
+
+    /// Stores a mail for `to`, returns `None` if their mailbox or `from`'s share of it is
+    /// over its quota
+    ///
+    /// The caller has to make sure `to` is a registered user. Concurrent mails to the same
+    /// recipient may overshoot their quota by the mails in flight
+    pub async fn store(
+        &self,
+        from: ShortIdStr,
+        to: &ShortIdStr,
+        envelope: MailEnvelope,
+    ) -> io::Result<Option<MailId>> {
+        let id = self.next_id();
+        let dir = user_path(&self.root, to);
+
+        spawn_blocking(move || {
+            fs::create_dir_all(&dir)?;
+
+            // Expired mail must not count against the quota
+            let mails = load(&dir)?;
+            let bytes: u64 = mails.iter().map(|(_, size)| size).sum();
+            let from_sender = mails.iter().filter(|(mail, _)| mail.from == from).count();
+            if mails.len() >= MAILBOX_MAX_MAILS
+                || from_sender >= MAILBOX_MAX_MAILS_PER_SENDER
+                || bytes + envelope.len() as u64 > MAILBOX_MAX_BYTES
+            {
+                return Ok(None);
+            }
+
+            let mail = S2CMail {
+                id,
+                from,
+                sent_at: unix_now(),
+                envelope,
+            };
+            let path = dir.join(format!("{id:016x}"));
+            let partial = path.with_extension("partial");
+            fs::write(&partial, mail.serialize_buffered())?;
+            fs::rename(partial, path)?;
+
+            Ok(Some(id))
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Mails waiting for `to`, oldest first, dropping the expired ones
+    pub async fn pending(&self, to: &ShortIdStr) -> io::Result<Vec<S2CMail>> {
+        let dir = user_path(&self.root, to);
+
+        spawn_blocking(move || Ok(load(&dir)?.into_iter().map(|(mail, _)| mail).collect()))
+            .await
+            .unwrap()
+    }
+
+    /// Removes a delivered mail, returns `false` if `to` had no such mail
+    pub async fn ack(&self, to: &ShortIdStr, id: MailId) -> io::Result<bool> {
+        let path = user_path(&self.root, to).join(format!("{id:016x}"));
+
+        spawn_blocking(move || match fs::remove_file(path) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Issues a new id, the millisecond timestamp in the high bits keeps ids increasing
+    /// across restarts
+    fn next_id(&self) -> MailId {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64;
+        let next = |last: MailId| (millis << 16).max(last + 1);
+
+        let last = self
+            .last_id
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(next(last))
+            })
+            .expect("Unreachable: the update always succeeds");
+        next(last)
+    }
+}
+
+/// Mails of a recipient directory with their size on disk, oldest first,
+/// removing the expired and corrupted ones
+///
+/// Mails acked or dropped by someone else meanwhile are skipped
+fn load(dir: &Path) -> io::Result<Vec<(S2CMail, u64)>> {
+    let now = unix_now();
+    let mut mails = Vec::new();
+
+    for (_, path) in list(dir)? {
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        let size = data.len() as u64;
+
+        match schemou::decode::<S2CMail>(&data) {
+            Ok(mail) if mail.sent_at + MAIL_TTL.as_secs() > now => mails.push((mail, size)),
+            Ok(_) => {
+                tracing::debug!(path = %path.display(), "dropping expired mail");
+                remove(&path)?;
+            }
+            Err(err) => {
+                tracing::warn!(path = %path.display(), "dropping corrupted mail: {err}");
+                remove(&path)?;
+            }
+        }
+    }
+
+    Ok(mails)
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Mail files of a recipient directory sorted by id, empty if the directory doesn't exist
+fn list(dir: &Path) -> io::Result<Vec<(MailId, PathBuf)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut mails = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let id = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| MailId::from_str_radix(name, 16).ok());
+
+        if let Some(id) = id {
+            mails.push((id, path));
+        }
+    }
+
+    mails.sort_unstable_by_key(|(id, _)| *id);
+    Ok(mails)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod mailbox_tests {
+    use super::{Mailbox, MAILBOX_MAX_MAILS, MAILBOX_MAX_MAILS_PER_SENDER};
+
+    use schemou::{
+        legos::{BoundedBytes, ShortIdStr},
+        S2CMail, Sirius,
+    };
+
+    use std::fs;
+
+    #[tokio::test]
+    async fn store_and_ack() {
+        let path = rand::random::<u64>().to_string();
+        let mailbox = Mailbox::open(&path).unwrap();
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        assert!(mailbox.pending(&thatmagicalcat).await.unwrap().is_empty());
+
+        let first = mailbox
+            .store(
+                duskyelf.clone(),
+                &thatmagicalcat,
+                BoundedBytes::new([1, 2, 3].as_slice()).unwrap(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        let second = mailbox
+            .store(
+                duskyelf.clone(),
+                &thatmagicalcat,
+                BoundedBytes::new([4, 5, 6].as_slice()).unwrap(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+        let pending = mailbox.pending(&thatmagicalcat).await.unwrap();
+        assert_eq!(
+            pending.iter().map(|mail| mail.id).collect::<Vec<_>>(),
+            [first, second]
+        );
+        assert_eq!(pending[0].from, duskyelf);
+        assert_eq!(&*pending[0].envelope, [1, 2, 3].as_slice());
+
+        assert!(mailbox.ack(&thatmagicalcat, first).await.unwrap());
+        assert!(!mailbox.ack(&thatmagicalcat, first).await.unwrap());
+        assert_eq!(mailbox.pending(&thatmagicalcat).await.unwrap().len(), 1);
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_stores() {
+        let path = rand::random::<u64>().to_string();
+        let mailbox = Mailbox::open(&path).unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        let stores = (0..16).map(|i| {
+            let (mailbox, to) = (mailbox.clone(), thatmagicalcat.clone());
+            tokio::spawn(async move {
+                let from = ShortIdStr::new(format!("sender{i}")).unwrap();
+                let envelope = BoundedBytes::new([i].as_slice()).unwrap();
+                mailbox.store(from, &to, envelope).await.unwrap().unwrap()
+            })
+        });
+        let mut ids = Vec::new();
+        for store in stores.collect::<Vec<_>>() {
+            ids.push(store.await.unwrap());
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 16);
+
+        let pending = mailbox.pending(&thatmagicalcat).await.unwrap();
+        assert_eq!(pending.iter().map(|mail| mail.id).collect::<Vec<_>>(), ids);
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn quota() {
+        let path = rand::random::<u64>().to_string();
+        let mailbox = Mailbox::open(&path).unwrap();
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        for i in 0..MAILBOX_MAX_MAILS {
+            let sender = ShortIdStr::new(format!("sender{}", i % 4)).unwrap();
+            let stored = mailbox
+                .store(
+                    sender,
+                    &thatmagicalcat,
+                    BoundedBytes::new([0].as_slice()).unwrap(),
+                )
+                .await
+                .unwrap();
+            assert!(stored.is_some());
+        }
+
+        let stored = mailbox
+            .store(
+                duskyelf.clone(),
+                &thatmagicalcat,
+                BoundedBytes::new([0].as_slice()).unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(stored.is_none());
+
+        // Quotas are per recipient
+        assert!(mailbox
+            .store(
+                thatmagicalcat,
+                &duskyelf,
+                BoundedBytes::new([0].as_slice()).unwrap(),
+            )
+            .await
+            .unwrap()
+            .is_some());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sender_quota() {
+        let path = rand::random::<u64>().to_string();
+        let mailbox = Mailbox::open(&path).unwrap();
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let ferris = ShortIdStr::new("ferris").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        let store = async |from: &ShortIdStr| {
+            mailbox
+                .store(
+                    from.clone(),
+                    &thatmagicalcat,
+                    BoundedBytes::new([0].as_slice()).unwrap(),
+                )
+                .await
+                .unwrap()
+        };
+
+        for _ in 0..MAILBOX_MAX_MAILS_PER_SENDER {
+            assert!(store(&duskyelf).await.is_some());
+        }
+        assert!(store(&duskyelf).await.is_none());
+
+        // Other senders still have room
+        assert!(store(&ferris).await.is_some());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_mail() {
+        let path = rand::random::<u64>().to_string();
+        let mailbox = Mailbox::open(&path).unwrap();
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        // A full mailbox of long expired mail
+        let dir = format!("{path}/{}", thatmagicalcat.as_str());
+        fs::create_dir_all(&dir).unwrap();
+        for id in 1..=MAILBOX_MAX_MAILS as u64 {
+            let mail = S2CMail {
+                id,
+                from: duskyelf.clone(),
+                sent_at: 0,
+                envelope: BoundedBytes::new([0].as_slice()).unwrap(),
+            };
+            fs::write(format!("{dir}/{id:016x}"), mail.serialize_buffered()).unwrap();
+        }
+
+        let stored = mailbox
+            .store(
+                duskyelf.clone(),
+                &thatmagicalcat,
+                BoundedBytes::new([1].as_slice()).unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(stored.is_some());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(path).unwrap();
+    }
+}
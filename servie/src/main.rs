@@ -1,8 +1,10 @@
 use schemou::*;
-use servie::*;
+use servie::{resume::RESUME_GRACE, *};
+
+use std::time::Duration;
 
 use axum::{
-    extract::{ws::WebSocket, State, WebSocketUpgrade},
+    extract::{State, WebSocketUpgrade},
     response::IntoResponse,
     routing::any,
     Router,
@@ -12,12 +14,6 @@ use rand_chacha::ChaCha20Rng;
 use tracing::Instrument;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-#[derive(Clone)]
-struct AppState {
-    mirror: Mirror,
-    user_channels: UserChannels,
-}
-
 #[tokio::main]
 async fn main() {
     tracing_subscriber::registry()
@@ -32,11 +28,67 @@ async fn main() {
     tracing::info!("loading .env");
     dotenvy::dotenv().expect("Failed to load .env file");
 
+    let mirror = Mirror::open_or_create()
+        .await
+        .expect("Could not connect to the DB");
+
+    let refresh_interval = std::env::var("MIRROR_REFRESH_SECS")
+        .map(|secs| secs.parse().expect("MIRROR_REFRESH_SECS is not a number"))
+        .unwrap_or(60);
+    mirror.spawn_refresh(Duration::from_secs(refresh_interval));
+
+    let mailbox = Mailbox::open_or_create().expect("Could not open the mailbox");
+    let blocklist = Blocklist::open_or_create().expect("Could not open the block list");
+    let contacts = Contacts::open_or_create().expect("Could not open the contacts");
+
+    let heartbeat = {
+        let secs = |var: &str, default: Duration| {
+            std::env::var(var)
+                .map(|secs| {
+                    let secs = secs
+                        .parse()
+                        .unwrap_or_else(|_| panic!("{var} is not a number"));
+                    Duration::from_secs(secs)
+                })
+                .unwrap_or(default)
+        };
+        let default = Heartbeat::default();
+        Heartbeat {
+            interval: secs("HEARTBEAT_INTERVAL_SECS", default.interval),
+            timeout: secs("HEARTBEAT_TIMEOUT_SECS", default.timeout),
+        }
+    };
+
+    let queue = {
+        let default = QueueConfig::default();
+        QueueConfig {
+            capacity: std::env::var("CHANNEL_CAPACITY")
+                .map(|capacity| {
+                    let capacity = capacity.parse().expect("CHANNEL_CAPACITY is not a number");
+                    // Nothing could ever be sent through an empty queue
+                    assert!(capacity > 0, "CHANNEL_CAPACITY must be at least 1");
+                    capacity
+                })
+                .unwrap_or(default.capacity),
+            overflow: std::env::var("CHANNEL_OVERFLOW")
+                .map(|overflow| {
+                    overflow
+                        .parse()
+                        .unwrap_or_else(|e| panic!("CHANNEL_OVERFLOW: {e}"))
+                })
+                .unwrap_or(default.overflow),
+        }
+    };
+
     let appstate = AppState {
-        mirror: Mirror::open_or_create()
-            .await
-            .expect("Could not connect to the DB"),
-        user_channels: UserChannels::new(),
+        mirror,
+        user_channels: UserChannels::with_config(queue),
+        mailbox,
+        blocklist,
+        contacts,
+        resumptions: Resumptions::new(),
+        relay: RelayQuota::new(),
+        heartbeat,
     };
 
     let router = Router::new()
@@ -50,122 +102,184 @@ async fn main() {
 }
 
 async fn connect(ws: WebSocketUpgrade, State(app_state): State<AppState>) -> impl IntoResponse {
-    ws.on_upgrade(|socket| async {
-        _ = handle_ws(socket, app_state).await;
+    ws.on_upgrade(|socket| async move {
+        let mut socket = LiveSocket::new(socket);
+        if let Err(err) = handle_ws(&mut socket, app_state).await {
+            tracing::debug!("closing connection: {err}");
+            err.report(&mut socket).await;
+        }
     })
 }
 
-async fn handle_ws(
-    mut socket: WebSocket,
-    AppState {
-        mirror,
-        user_channels,
-    }: AppState,
-) -> Result<()> {
-    let C2SAck { username } = socket.recv_de().await?;
-
-    if user_channels.is_online(&username).await {
-        return Err(ServieError::NonCompliance("User is already online"));
-    }
-
-    // TODO: Use commit id from the clientie as a hint that registrie might need to be refetched
-    // Issue URL: https://github.com/Colabie/Colabie/issues/61
-    // labels: enhancement, good first issue, discussion
-    let _record = mirror
-        .lookup_record(username.clone())
+async fn handle_ws(socket: &mut LiveSocket, state: AppState) -> Result<()> {
+    // Connections that never finish logging in are reaped like silent sessions
+    let deadline = state.heartbeat.timeout;
+    let slot = tokio::time::timeout(deadline, handshake(socket, &state))
         .await
-        // TODO: Ban IPs in case of failed login
-        // Issue URL: https://github.com/Colabie/Colabie/issues/60
-        // labels: enhancement, discussion
-        .ok_or_else(|| ServieError::NonCompliance("Invalid username"))?;
+        .map_err(|_| {
+            tracing::info!("reaping a connection that didn't log in in time");
+            ServieError::Unresponsive(deadline)
+        })??;
 
-    let mut rng = ChaCha20Rng::from_os_rng();
-    let auth_req = S2CAuthReq {
-        random: rng.random(),
+    serve(socket, slot, state).await
+}
+
+/// Takes the connection from `C2SHello` to a logged in or resumed device
+async fn handshake(socket: &mut LiveSocket, state: &AppState) -> Result<session::Slot> {
+    let C2S::Hello(C2SHello { version }) = socket.recv_de().await? else {
+        return Err(ServieError::NonCompliance("Expected C2SHello"));
     };
-    socket.send_se(auth_req).await?;
 
-    // TODO: Verify the User and signed random
-    // labels: enhancement
-    // Issue URL: https://github.com/Colabie/Colabie/issues/54
-    // coupled with #4
-    let C2SAuthRes { signed_random: _ } = socket.recv_de().await?;
+    if version != PROTOCOL_VERSION {
+        return Err(ServieError::IncompatibleVersion(version));
+    }
+    socket
+        .send_se(S2C::Hello(S2CHello {
+            version: PROTOCOL_VERSION,
+        }))
+        .await?;
 
-    let user_span = tracing::debug_span!("user", username = *username);
-    async move {
-        socket.send_se(S2CAuthResult::Success).await?;
-        tracing::debug!("User connected");
+    let slot = match socket.recv_de().await? {
+        C2S::Ack(ack) => login(socket, ack, state).await?,
+        C2S::Resume(C2SResume {
+            username,
+            device,
+            token,
+        }) => {
+            let addr = DeviceAddr { username, device };
 
-        let mut self_channel = SelfChannel::new(username.clone(), user_channels.clone()).await;
-        loop {
-            tokio::select! {
-                ws_recv = socket.recv_de() => {
-                    let ConnectToUser { username: other_username } = ws_recv?;
-                    // TODO: Ban IPs in case of invalid username
-                    // Issue URL: https://github.com/Colabie/Colabie/issues/74
-                    // labels: enhancement, discussion
-                    let Some(other) = user_channels.get(&other_username).await else {
-                        socket.send_se(S2CConnectToUserResult::UserBusy).await?;
-                        continue;
-                    };
+            // The old socket of the device might not have been noticed dead yet
+            state
+                .resumptions
+                .take_over(&addr, Some(&token), Duration::ZERO)
+                .await;
+            match state.resumptions.take(&addr, &token).await {
+                Some(slot) => {
+                    socket
+                        .send_se(S2C::ResumeResult(S2CResumeResult::Success))
+                        .await?;
+                    slot
+                }
+                None => {
+                    socket
+                        .send_se(S2C::ResumeResult(S2CResumeResult::Failure))
+                        .await?;
 
-                    // try_tell on the first interaction, but wait for next times
-                    let Ok(_) = other.try_tell(&username, ChannelMsg::ConnectToUser) else {
-                        socket.send_se(S2CConnectToUserResult::UserBusy).await?;
-                        continue;
+                    let C2S::Ack(ack) = socket.recv_de().await? else {
+                        return Err(ServieError::NonCompliance("Expected C2SAck"));
                     };
-
-                    match self_channel.listen(&other_username).await {
-                        Some(ChannelMsg::ConnectToUserReject) => {
-                            socket.send_se(S2CConnectToUserResult::Reject).await?;
-                            continue;
-                        }
-
-                        Some(ChannelMsg::UserBusy) | None => {
-                            socket.send_se(S2CConnectToUserResult::UserBusy).await?;
-                            continue;
-                        }
-
-                        // Implicitly accept if the other user also tries to connect at the same time
-                        Some(ChannelMsg::ConnectToUserAccept | ChannelMsg::ConnectToUser) => {
-                            socket.send_se(S2CConnectToUserResult::Accept).await?;
-                        }
-                    }
+                    login(socket, ack, state).await?
                 }
+            }
+        }
+        _ => return Err(ServieError::NonCompliance("Expected C2SAck or C2SResume")),
+    };
 
-                ChannelMsgWithSender { from, message } = self_channel.hear() => {
-                    match message {
-                        ChannelMsg::ConnectToUser => {
-                            socket.send_se(ConnectToUser { username: from.clone() }).await?;
-                            let connect = socket.recv_de::<C2SConnectToUserResult>().await?;
-
-                            let Some(other) = user_channels
-                                .get(&from)
-                                .await else {
-                                    continue;
-                                };
-
-                            match connect {
-                                C2SConnectToUserResult::Reject => {
-                                    _ = other.try_tell(
-                                        &username,
-                                        ChannelMsg::ConnectToUserReject,
-                                    );
-                                }
-                                C2SConnectToUserResult::Accept => {
-                                    let Ok(_) = other.tell(&username, ChannelMsg::ConnectToUserAccept).await else {
-                                        continue;
-                                    };
-                                }
-                            }
-                        }
-
-                        _ => unreachable!("Inappropriate message from self channel"),
-                    }
-                }
+    Ok(slot)
+}
+
+/// Serves a logged in device, parking it for resumption if its socket closes
+async fn serve(socket: &mut LiveSocket, mut slot: session::Slot, state: AppState) -> Result<()> {
+    let addr = slot.addr();
+    let user_span = tracing::debug_span!("user", username = *addr.username, device = addr.device);
+    async move {
+        tracing::debug!("User connected");
+
+        let token = ChaCha20Rng::from_os_rng().random();
+        let result = match socket
+            .send_se(S2C::ResumeToken(S2CResumeToken {
+                token,
+                grace_secs: RESUME_GRACE.as_secs() as u32,
+            }))
+            .await
+        {
+            Ok(()) => {
+                state.resumptions.go_live(token, &slot).await;
+                let result = session::run(socket, &mut slot, &state).await;
+                state.resumptions.leave(slot.addr(), &token).await;
+                result
             }
+            Err(err) => Err(err),
+        };
+
+        // Keep the device online for a while if it only lost its connection, reaped ones
+        // included, so their pending requests and mail survive a reconnect
+        if let Err(
+            ServieError::SocketClosed
+            | ServieError::AxumError(_)
+            | ServieError::Unresponsive(_)
+            | ServieError::TakenOver,
+        ) = result
+        {
+            tracing::debug!("parking the session for resumption");
+            state.resumptions.park(token, slot).await;
         }
+        result
     }
     .instrument(user_span)
     .await
 }
+
+/// Authenticates the user of `ack` from scratch, and takes their device online
+async fn login(socket: &mut LiveSocket, ack: C2SAck, state: &AppState) -> Result<session::Slot> {
+    let C2SAck {
+        username,
+        device,
+        commit_hint,
+        hide_presence,
+    } = ack;
+
+    // The device itself doesn't count, a successful login takes over its slot
+    let addr = DeviceAddr {
+        username: username.clone(),
+        device,
+    };
+    if state.user_channels.device_count(&username).await >= MAX_DEVICES
+        && !state.user_channels.is_device_online(&addr).await
+    {
+        return Err(ServieError::TooManyDevices);
+    }
+
+    let commit_hint = commit_hint.and_then(|commit_hint| git2::Oid::from_bytes(&commit_hint).ok());
+    let record = state
+        .mirror
+        .lookup_record_hinted(username.clone(), commit_hint)
+        .await
+        // TODO: Ban IPs in case of failed login
+        // Issue URL: https://github.com/Colabie/Colabie/issues/60
+        // labels: enhancement, discussion
+        .ok_or(ServieError::UnknownUser)?;
+
+    let mut rng = ChaCha20Rng::from_os_rng();
+    let random = rng.random();
+    socket.send_se(S2C::AuthReq(S2CAuthReq { random })).await?;
+
+    let C2S::AuthRes(C2SAuthRes { signature }) = socket.recv_de().await? else {
+        return Err(ServieError::NonCompliance("Expected C2SAuthRes"));
+    };
+
+    if !record.verify(&random, &signature, AUTH_CONTEXT) {
+        socket
+            .send_se(S2C::AuthResult(S2CAuthResult::Failure))
+            .await?;
+        return Err(ServieError::AuthFailed);
+    }
+
+    // A parked device is online, but taken over by a successful login, so is a served one
+    // silent for longer than the pongs of a live connection take
+    if state.user_channels.is_device_online(&addr).await
+        && !state.resumptions.is_parked(&addr).await
+        && !state
+            .resumptions
+            .take_over(&addr, None, 2 * state.heartbeat.interval)
+            .await
+    {
+        return Err(ServieError::AlreadyOnline);
+    }
+    state.resumptions.evict(&addr).await;
+    socket
+        .send_se(S2C::AuthResult(S2CAuthResult::Success))
+        .await?;
+
+    Ok(session::Slot::open(addr, hide_presence, &state.user_channels).await)
+}
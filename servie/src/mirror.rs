@@ -1,14 +1,30 @@
-use registrie::{lookup_record, Record, DEFAULT_BRANCH};
+use registrie::{
+    lookup_record, signing::load_verifying_key, verify_history, Record, VerifyingKey,
+    DEFAULT_BRANCH,
+};
 use schemou::legos::ShortIdStr;
 
-use std::{fs, sync::Arc};
+use std::{fs, sync::Arc, time::Duration};
 
-use git2::{build::RepoBuilder, Error, Repository};
-use tokio::{sync::Mutex, task::spawn_blocking};
+use git2::{build::RepoBuilder, Error, Oid, Repository};
+use tokio::{
+    sync::Mutex,
+    task::{spawn_blocking, JoinHandle},
+    time::Instant,
+};
+
+/// Minimum time between two on-demand refreshes, see `Mirror::refresh`
+const ON_DEMAND_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Clone)]
 pub struct Mirror {
     git: Arc<Mutex<Repository>>,
+    /// Upstream registrie repo the mirror fetches from
+    url: Arc<str>,
+    /// Every commit the mirror accepts must be signed by this key
+    registrie_key: Arc<VerifyingKey>,
+    /// When the last on-demand refresh happened
+    last_refresh: Arc<Mutex<Option<Instant>>>,
 }
 
 impl Mirror {
@@ -29,31 +45,72 @@ impl Mirror {
             }
         };
 
-        if let Ok(repo) = Repository::open_bare(&path) {
+        let registrie_key = {
+            let key_path = std::env::var("REGISTRIE_PUBKEY_PATH")
+                .expect("REGISTRIE_PUBKEY_PATH environment variable not set");
+            load_verifying_key(&key_path).expect("Failed to load registrie's public key")
+        };
+
+        Self::open(&path, &url, registrie_key).await
+    }
+
+    // This function blocks on io operations
+    // That's fine as it's called once at the very start
+    pub async fn open(path: &str, url: &str, registrie_key: VerifyingKey) -> Result<Self, Error> {
+        let registrie_key = Arc::new(registrie_key);
+
+        if let Ok(repo) = Repository::open_bare(path) {
             let mirror = Self {
                 git: Arc::new(Mutex::new(repo)),
+                url: url.into(),
+                registrie_key,
+                last_refresh: Default::default(),
             };
 
             mirror.fetch_db().await?;
             Ok::<_, Error>(mirror)
         } else {
             tracing::info!("cloning registrie");
+            let repo = RepoBuilder::new()
+                .bare(true)
+                .branch(DEFAULT_BRANCH)
+                .clone(url, std::path::Path::new(path))?;
+
+            let verified = repo
+                .find_branch(DEFAULT_BRANCH, git2::BranchType::Local)
+                .and_then(|branch| branch.into_reference().peel_to_commit())
+                .and_then(|head| verify_history(&repo, head.id(), None, &registrie_key));
+
+            if let Err(err) = verified {
+                // Don't leave an unverified clone behind, it would be trusted on the next start
+                drop(repo);
+                _ = fs::remove_dir_all(path);
+                return Err(err);
+            }
+
             Ok(Self {
-                git: Arc::new(Mutex::new(
-                    RepoBuilder::new()
-                        .bare(true)
-                        .clone(&url, std::path::Path::new(&path))?,
-                )),
+                git: Arc::new(Mutex::new(repo)),
+                url: url.into(),
+                registrie_key,
+                last_refresh: Default::default(),
             })
         }
     }
 
-    pub async fn fetch_db(&self) -> Result<(), Error> {
-        tracing::info!("fetching registrie");
+    /// Fetches `DEFAULT_BRANCH` from upstream and fast-forwards the mirror to it
+    ///
+    /// Returns the number of newly applied commits
+    pub async fn fetch_db(&self) -> Result<usize, Error> {
+        tracing::debug!("fetching registrie");
         let repo = self.clone();
         spawn_blocking(move || {
+            let registrie_key = repo.registrie_key.clone();
+            let url = repo.url.clone();
             let repo = repo.git.blocking_lock();
 
+            repo.remote_anonymous(&url)?
+                .fetch(&[DEFAULT_BRANCH], None, Some("Fetch Mirror"))?;
+
             let (merge_analysis, _) = {
                 let annotated_commit =
                     repo.reference_to_annotated_commit(&repo.find_reference("FETCH_HEAD")?)?;
@@ -61,23 +118,85 @@ impl Mirror {
             };
 
             if merge_analysis.is_up_to_date() {
-                return Ok(());
+                return Ok(0);
             }
 
             if merge_analysis.is_fast_forward() {
-                repo.find_branch(DEFAULT_BRANCH, git2::BranchType::Local)
+                let mut reference = repo
+                    .find_branch(DEFAULT_BRANCH, git2::BranchType::Local)
                     .expect("Default Branch")
-                    .into_reference()
-                    .set_target(
-                        repo.reference_to_annotated_commit(&repo.find_reference("FETCH_HEAD")?)?
-                            .id(),
-                        "Fetch Mirror",
-                    )?;
+                    .into_reference();
+
+                let fetched = repo
+                    .reference_to_annotated_commit(&repo.find_reference("FETCH_HEAD")?)?
+                    .id();
+
+                // Refuse the whole update if any new commit isn't signed by registrie
+                let applied = verify_history(&repo, fetched, reference.target(), &registrie_key)?;
+
+                reference.set_target(fetched, "Fetch Mirror")?;
+                tracing::info!(applied, head = %fetched, "applied registrie commits");
+
+                Ok(applied)
             } else {
-                return Err(Error::from_str("Fast-forward only!"));
+                Err(Error::from_str("Fast-forward only!"))
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Keeps the mirror fresh by fetching upstream every `interval`
+    pub fn spawn_refresh(&self, interval: Duration) -> JoinHandle<()> {
+        let mirror = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            // The first tick completes immediately, and the mirror was just fetched on open
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+                if let Err(err) = mirror.fetch_db().await {
+                    tracing::warn!("failed to fetch registrie: {err}");
+                }
             }
+        })
+    }
+
+    /// Fetches upstream on demand, at most once every `ON_DEMAND_REFRESH_INTERVAL`
+    ///
+    /// Returns `true` if the mirror was actually refreshed
+    pub async fn refresh(&self) -> bool {
+        // Concurrent callers wait on the lock and then see the fresh timestamp,
+        // so a burst of stale lookups results in a single fetch
+        let mut last_refresh = self.last_refresh.lock().await;
+        if last_refresh.is_some_and(|last| last.elapsed() < ON_DEMAND_REFRESH_INTERVAL) {
+            return false;
+        }
+        *last_refresh = Some(Instant::now());
 
-            Ok(())
+        match self.fetch_db().await {
+            Ok(_) => true,
+            Err(err) => {
+                tracing::warn!("failed to refresh registrie: {err}");
+                false
+            }
+        }
+    }
+
+    /// Checks if `commit` is part of the mirror's `DEFAULT_BRANCH`
+    pub async fn has_commit(&self, commit: Oid) -> bool {
+        let git = self.git.clone();
+        spawn_blocking(move || {
+            let repo = git.blocking_lock();
+            let head = repo
+                .find_branch(DEFAULT_BRANCH, git2::BranchType::Local)
+                .expect("Default Branch")
+                .into_reference()
+                .target()
+                .expect("Unreachable: symbolic default branch");
+
+            head == commit || repo.graph_descendant_of(head, commit).unwrap_or(false)
         })
         .await
         .unwrap()
@@ -88,4 +207,135 @@ impl Mirror {
             .await
             .expect("Git database not accessible")
     }
+
+    /// Looks up a record, refreshing the mirror first if it misses
+    /// or if it doesn't know the client's `commit_hint` yet
+    pub async fn lookup_record_hinted(
+        &self,
+        username: ShortIdStr,
+        commit_hint: Option<Oid>,
+    ) -> Option<Record> {
+        let stale = match commit_hint {
+            Some(commit) => !self.has_commit(commit).await,
+            None => false,
+        };
+
+        let record = self.clone().lookup_record(username.clone()).await;
+        if record.is_some() && !stale {
+            return record;
+        }
+
+        tracing::debug!(stale, "mirror lookup missed, refreshing");
+        match self.refresh().await {
+            true => self.clone().lookup_record(username).await,
+            false => record,
+        }
+    }
+}
+
+#[cfg(test)]
+mod mirror_tests {
+    use super::Mirror;
+    use crate::test_utils::{file_url, init_upstream};
+
+    use registrie::new_record;
+    use schemou::legos::ShortIdStr;
+
+    use std::{fs, sync::Arc};
+
+    use fips204::ml_dsa_87;
+
+    #[tokio::test]
+    async fn fetch_new_records() {
+        let upstream_path = rand::random::<u64>().to_string();
+        let mirror_path = rand::random::<u64>().to_string();
+        let (verifying_key, key) = ml_dsa_87::try_keygen().unwrap();
+        let key = Arc::new(key);
+
+        let upstream = init_upstream(&upstream_path, &key);
+        let mirror = Mirror::open(&mirror_path, &file_url(&upstream_path), verifying_key)
+            .await
+            .unwrap();
+
+        let username = ShortIdStr::new("duskyelf").unwrap();
+        assert!(mirror
+            .clone()
+            .lookup_record(username.clone())
+            .await
+            .is_none());
+
+        new_record(upstream, key, username.clone(), [1, 2, 3].into())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(mirror.fetch_db().await.unwrap(), 1);
+        assert!(mirror.clone().lookup_record(username).await.is_some());
+        assert_eq!(mirror.fetch_db().await.unwrap(), 0);
+
+        fs::remove_dir_all(upstream_path).unwrap();
+        fs::remove_dir_all(mirror_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lookup_with_hint() {
+        let upstream_path = rand::random::<u64>().to_string();
+        let mirror_path = rand::random::<u64>().to_string();
+        let (verifying_key, key) = ml_dsa_87::try_keygen().unwrap();
+        let key = Arc::new(key);
+
+        let upstream = init_upstream(&upstream_path, &key);
+        let mirror = Mirror::open(&mirror_path, &file_url(&upstream_path), verifying_key)
+            .await
+            .unwrap();
+
+        let username = ShortIdStr::new("duskyelf").unwrap();
+        let commit = new_record(upstream, key, username.clone(), [1, 2, 3].into())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(!mirror.has_commit(commit).await);
+        assert!(mirror
+            .lookup_record_hinted(username.clone(), Some(commit))
+            .await
+            .is_some());
+        assert!(mirror.has_commit(commit).await);
+
+        // Refreshes are rate limited
+        assert!(!mirror.refresh().await);
+
+        fs::remove_dir_all(upstream_path).unwrap();
+        fs::remove_dir_all(mirror_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_foreign_commits() {
+        let upstream_path = rand::random::<u64>().to_string();
+        let mirror_path = rand::random::<u64>().to_string();
+        let (verifying_key, key) = ml_dsa_87::try_keygen().unwrap();
+        let (_, foreign_key) = ml_dsa_87::try_keygen().unwrap();
+
+        let upstream = init_upstream(&upstream_path, &key);
+        let mirror = Mirror::open(&mirror_path, &file_url(&upstream_path), verifying_key)
+            .await
+            .unwrap();
+
+        let username = ShortIdStr::new("duskyelf").unwrap();
+        new_record(
+            upstream,
+            Arc::new(foreign_key),
+            username.clone(),
+            [1, 2, 3].into(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert!(mirror.fetch_db().await.is_err());
+        assert!(mirror.lookup_record(username).await.is_none());
+
+        fs::remove_dir_all(upstream_path).unwrap();
+        fs::remove_dir_all(mirror_path).unwrap();
+    }
 }
@@ -0,0 +1,307 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::Notify,
+    time::{timeout_at, Instant},
+};
+
+/// What sending to a full queue does
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// The message being sent is refused
+    RejectNewest,
+    /// The oldest queued message is dropped to make room
+    DropOldest,
+    /// The sender waits up to this long for room, then the message is refused
+    Wait(Duration),
+}
+
+/// Parses `reject-newest`, `drop-oldest` or `wait:<millis>`
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject-newest" => Ok(Overflow::RejectNewest),
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            _ => s
+                .strip_prefix("wait:")
+                .and_then(|millis| millis.parse().ok())
+                .map(|millis| Overflow::Wait(Duration::from_millis(millis)))
+                .ok_or_else(|| format!("Unknown overflow policy {s:?}")),
+        }
+    }
+}
+
+/// Capacity and overflow policy of the queue behind every device's channel
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            overflow: Overflow::RejectNewest,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SendError {
+    #[error("Queue is full")]
+    Full,
+
+    #[error("Receiver is gone")]
+    Closed,
+}
+
+/// Bounded multi-producer single-consumer queue, unlike `tokio::sync::mpsc` its senders
+/// can drop the oldest message to make room
+pub fn queue<T>(config: QueueConfig, total_overflows: Arc<AtomicU64>) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        items: Mutex::new(VecDeque::with_capacity(config.capacity)),
+        config,
+        readable: Notify::new(),
+        writable: Notify::new(),
+        closed: AtomicBool::new(false),
+        overflows: AtomicU64::new(0),
+        total_overflows,
+    });
+
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+struct Shared<T> {
+    items: Mutex<VecDeque<T>>,
+    config: QueueConfig,
+    readable: Notify,
+    writable: Notify,
+    closed: AtomicBool,
+    overflows: AtomicU64,
+    /// Shared by every queue, for the server wide count
+    total_overflows: Arc<AtomicU64>,
+}
+
+impl<T> Shared<T> {
+    fn overflowed(&self) {
+        let overflows = self.overflows.fetch_add(1, Ordering::Relaxed) + 1;
+        let total = self.total_overflows.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::debug!(overflows, total, policy = ?self.config.overflow, "channel overflowed");
+    }
+
+    /// Queues `item` if there's room, hands it back otherwise
+    fn push(&self, item: T) -> Result<(), T> {
+        let mut items = self.items.lock().unwrap();
+        if items.len() >= self.config.capacity {
+            return Err(item);
+        }
+
+        items.push_back(item);
+        drop(items);
+        self.readable.notify_one();
+        Ok(())
+    }
+}
+
+pub struct Sender<T>(Arc<Shared<T>>);
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("config", &self.0.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Sender<T> {
+    /// Queues `item` following the queue's overflow policy
+    pub async fn send(&self, item: T) -> Result<(), SendError> {
+        match self.0.config.overflow {
+            Overflow::RejectNewest => self.try_send(item),
+            Overflow::DropOldest => {
+                if self.0.closed.load(Ordering::Relaxed) {
+                    return Err(SendError::Closed);
+                }
+
+                let mut items = self.0.items.lock().unwrap();
+                if items.len() >= self.0.config.capacity {
+                    items.pop_front();
+                    self.0.overflowed();
+                }
+                items.push_back(item);
+                drop(items);
+
+                self.0.readable.notify_one();
+                Ok(())
+            }
+            Overflow::Wait(wait) => self.send_within(item, wait).await,
+        }
+    }
+
+    /// Queues `item` only if there's room right away, whatever the overflow policy
+    pub fn try_send(&self, item: T) -> Result<(), SendError> {
+        if self.0.closed.load(Ordering::Relaxed) {
+            return Err(SendError::Closed);
+        }
+
+        self.0.push(item).map_err(|_| {
+            self.0.overflowed();
+            SendError::Full
+        })
+    }
+
+    /// Waits up to `wait` for room to queue `item`, whatever the overflow policy
+    pub async fn send_within(&self, mut item: T, wait: Duration) -> Result<(), SendError> {
+        let deadline = Instant::now() + wait;
+
+        loop {
+            if self.0.closed.load(Ordering::Relaxed) {
+                return Err(SendError::Closed);
+            }
+
+            // Registered before checking, so room made in between isn't missed
+            let writable = self.0.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+
+            match self.0.push(item) {
+                Ok(()) => return Ok(()),
+                Err(rejected) => item = rejected,
+            }
+
+            if timeout_at(deadline, writable).await.is_err() {
+                self.0.overflowed();
+                return Err(SendError::Full);
+            }
+        }
+    }
+}
+
+pub struct Receiver<T>(Arc<Shared<T>>);
+
+impl<T> Receiver<T> {
+    /// Waits for the next message, cancel safe
+    pub async fn recv(&mut self) -> T {
+        loop {
+            let readable = self.0.readable.notified();
+            tokio::pin!(readable);
+            readable.as_mut().enable();
+
+            if let Some(item) = self.0.items.lock().unwrap().pop_front() {
+                self.0.writable.notify_one();
+                return item;
+            }
+
+            readable.await;
+        }
+    }
+
+    /// Messages refused or dropped because the queue was full
+    pub fn overflows(&self) -> u64 {
+        self.0.overflows.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.0.closed.store(true, Ordering::Relaxed);
+        // Don't keep senders waiting for room that will never come
+        self.0.writable.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod queue_tests {
+    use super::{queue, Overflow, QueueConfig, SendError};
+
+    use std::{sync::Arc, time::Duration};
+
+    fn config(overflow: Overflow) -> QueueConfig {
+        QueueConfig {
+            capacity: 2,
+            overflow,
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_newest() {
+        let (tx, mut rx) = queue(config(Overflow::RejectNewest), Default::default());
+
+        tx.send(1).await.unwrap();
+        tx.send(2).await.unwrap();
+        assert_eq!(tx.send(3).await, Err(SendError::Full));
+
+        assert_eq!(rx.recv().await, 1);
+        assert_eq!(rx.recv().await, 2);
+        assert_eq!(rx.overflows(), 1);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let total = Arc::default();
+        let (tx, mut rx) = queue(config(Overflow::DropOldest), Arc::clone(&total));
+
+        for i in 1..=4 {
+            tx.send(i).await.unwrap();
+        }
+        assert_eq!(rx.recv().await, 3);
+        assert_eq!(rx.recv().await, 4);
+        assert_eq!(rx.overflows(), 2);
+        assert_eq!(total.load(std::sync::atomic::Ordering::Relaxed), 2);
+
+        drop(rx);
+        assert_eq!(tx.send(5).await, Err(SendError::Closed));
+    }
+
+    #[tokio::test]
+    async fn wait() {
+        let (tx, mut rx) = queue(
+            config(Overflow::Wait(Duration::from_millis(50))),
+            Default::default(),
+        );
+
+        tx.send(1).await.unwrap();
+        tx.send(2).await.unwrap();
+        assert_eq!(tx.send(3).await, Err(SendError::Full));
+
+        // Room made while waiting lets the message in
+        let sender = tokio::spawn({
+            let tx = tx.clone();
+            async move { tx.send(3).await }
+        });
+        assert_eq!(rx.recv().await, 1);
+        sender.await.unwrap().unwrap();
+
+        assert_eq!(rx.recv().await, 2);
+        assert_eq!(rx.recv().await, 3);
+        assert_eq!(rx.overflows(), 1);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("drop-oldest".parse(), Ok(Overflow::DropOldest));
+        assert_eq!(
+            "wait:250".parse(),
+            Ok(Overflow::Wait(Duration::from_millis(250)))
+        );
+        assert!("wait:soon".parse::<Overflow>().is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use schemou::legos::ShortIdStr;
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use tokio::{sync::Mutex, time::Instant};
+
+/// Bytes of `RelayFrame`s a user may send within `RELAY_WINDOW`, from all their devices
+pub const RELAY_QUOTA: u64 = 256 * 1024 * 1024;
+
+pub const RELAY_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Relay usage of every user, kept across their reconnects
+#[derive(Clone, Default)]
+pub struct RelayQuota(Arc<Mutex<HashMap<ShortIdStr, Usage>>>);
+
+struct Usage {
+    /// Start of the user's current window
+    since: Instant,
+    bytes: u64,
+}
+
+impl RelayQuota {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts `len` bytes relayed by `user`, returns `false` once they are over `RELAY_QUOTA`
+    pub async fn charge(&self, user: &ShortIdStr, len: usize) -> bool {
+        self.charge_at(user, len, Instant::now()).await
+    }
+
+    async fn charge_at(&self, user: &ShortIdStr, len: usize, now: Instant) -> bool {
+        let mut usage = self.0.lock().await;
+        let in_window = |usage: &Usage| now.duration_since(usage.since) < RELAY_WINDOW;
+
+        if !usage.get(user).is_some_and(in_window) {
+            // Forget the users whose window is over, at most once a window per user
+            usage.retain(|_, usage| in_window(usage));
+            usage.insert(
+                user.clone(),
+                Usage {
+                    since: now,
+                    bytes: 0,
+                },
+            );
+        }
+
+        let usage = usage.get_mut(user).expect("Unreachable: inserted above");
+        usage.bytes += len as u64;
+        if usage.bytes > RELAY_QUOTA {
+            tracing::info!(relayed = usage.bytes, "user is over the relay quota");
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod relay_tests {
+    use super::{RelayQuota, RELAY_QUOTA, RELAY_WINDOW};
+
+    use schemou::legos::ShortIdStr;
+
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn quota() {
+        let quota = RelayQuota::new();
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        assert!(quota.charge(&duskyelf, RELAY_QUOTA as usize).await);
+        assert!(!quota.charge(&duskyelf, 1).await);
+
+        // Quotas are per user, and reset with the window
+        assert!(quota.charge(&thatmagicalcat, 1).await);
+        let later = Instant::now() + RELAY_WINDOW;
+        assert!(quota.charge_at(&duskyelf, 1, later).await);
+    }
+}
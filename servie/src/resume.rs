@@ -0,0 +1,213 @@
+use crate::{session::Slot, DeviceAddr};
+
+use schemou::ResumeToken;
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{mpsc, oneshot, Mutex},
+    time::sleep,
+};
+
+/// How long a device that lost its socket stays online, waiting to be resumed
+pub const RESUME_GRACE: Duration = Duration::from_secs(30);
+
+/// Slots of devices that lost their socket, kept for `RESUME_GRACE` in case they reconnect
+///
+/// A device can reconnect before servie notices its old socket is dead, so the slots of devices
+/// being served can be taken over too
+#[derive(Clone, Default)]
+pub struct Resumptions {
+    parked: Arc<Mutex<HashMap<DeviceAddr, Parked>>>,
+    live: Arc<Mutex<HashMap<DeviceAddr, Live>>>,
+}
+
+struct Parked {
+    token: ResumeToken,
+    slot: Slot,
+}
+
+/// A device being served, with the token its slot would be parked with
+struct Live {
+    token: ResumeToken,
+    takeovers: mpsc::Sender<Takeover>,
+}
+
+/// Asks the session serving a slot to give it up to a new connection of the same device
+pub(crate) struct Takeover {
+    /// Only given up if its socket was silent for at least this long
+    pub min_silence: Duration,
+    /// Told once the slot is parked, dropped if the session keeps it
+    pub parked: oneshot::Sender<()>,
+}
+
+impl Resumptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps `slot` online for `RESUME_GRACE`, for whoever presents `token`
+    pub async fn park(&self, token: ResumeToken, mut slot: Slot) {
+        let addr = slot.addr().clone();
+        let taken_over = slot.taken_over();
+        self.parked
+            .lock()
+            .await
+            .insert(addr.clone(), Parked { token, slot });
+        if let Some(taken_over) = taken_over {
+            _ = taken_over.send(());
+        }
+
+        let parked = self.parked.clone();
+        tokio::spawn(async move {
+            sleep(RESUME_GRACE).await;
+
+            // Unless it was resumed, and maybe parked again with another token
+            let mut parked = parked.lock().await;
+            if parked.get(&addr).is_some_and(|p| p.token == token) {
+                tracing::debug!(
+                    username = *addr.username,
+                    device = addr.device,
+                    "resumption grace period over"
+                );
+                parked.remove(&addr);
+            }
+        });
+    }
+
+    /// Takes back the slot parked for `addr` if `token` is the one it was parked with
+    ///
+    /// A token works once, a wrong one leaves the slot parked
+    pub async fn take(&self, addr: &DeviceAddr, token: &ResumeToken) -> Option<Slot> {
+        let mut parked = self.parked.lock().await;
+        if !parked.get(addr).is_some_and(|p| tokens_eq(&p.token, token)) {
+            return None;
+        }
+
+        parked.remove(addr).map(|parked| parked.slot)
+    }
+
+    pub async fn is_parked(&self, addr: &DeviceAddr) -> bool {
+        self.parked.lock().await.contains_key(addr)
+    }
+
+    /// Takes `addr` offline if it's parked, eg. as it logged in again from scratch
+    pub async fn evict(&self, addr: &DeviceAddr) {
+        let parked = self.parked.lock().await.remove(addr);
+        if let Some(parked) = parked {
+            parked.slot.close().await;
+        }
+    }
+
+    /// Lets `slot`, now being served, be taken over by a connection presenting `token`
+    /// or authenticating as its device again
+    pub async fn go_live(&self, token: ResumeToken, slot: &Slot) {
+        let live = Live {
+            token,
+            takeovers: slot.takeovers(),
+        };
+        self.live.lock().await.insert(slot.addr().clone(), live);
+    }
+
+    /// Ends `go_live`, unless `addr` went live again with another token meanwhile
+    pub async fn leave(&self, addr: &DeviceAddr, token: &ResumeToken) {
+        let mut live = self.live.lock().await;
+        if live.get(addr).is_some_and(|live| live.token == *token) {
+            live.remove(addr);
+        }
+    }
+
+    /// Asks the session serving `addr` to park its slot if its socket was silent for at least
+    /// `min_silence`, returns once the slot is parked, `false` if the session kept it
+    ///
+    /// With `token`, only the session that would be parked with it is asked
+    pub async fn take_over(
+        &self,
+        addr: &DeviceAddr,
+        token: Option<&ResumeToken>,
+        min_silence: Duration,
+    ) -> bool {
+        let takeovers = match self.live.lock().await.get(addr) {
+            Some(live) if token.is_none_or(|token| tokens_eq(&live.token, token)) => {
+                live.takeovers.clone()
+            }
+            _ => return false,
+        };
+
+        let (parked, is_parked) = oneshot::channel();
+        let takeover = Takeover {
+            min_silence,
+            parked,
+        };
+        takeovers.send(takeover).await.is_ok() && is_parked.await.is_ok()
+    }
+}
+
+/// Compares in constant time, not to leak how much of a guessed token is right
+fn tokens_eq(a: &ResumeToken, b: &ResumeToken) -> bool {
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod resume_tests {
+    use super::Resumptions;
+    use crate::{session::Slot, DeviceAddr, UserChannels};
+
+    use schemou::legos::ShortIdStr;
+
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn single_use() {
+        let channels = UserChannels::new();
+        let resumptions = Resumptions::new();
+        let addr = DeviceAddr {
+            username: ShortIdStr::new("duskyelf").unwrap(),
+            device: 1,
+        };
+
+        let slot = Slot::open(addr.clone(), false, &channels).await;
+        resumptions.park([7; 32], slot).await;
+        assert!(channels.is_device_online(&addr).await);
+
+        assert!(resumptions.take(&addr, &[8; 32]).await.is_none());
+        let slot = resumptions.take(&addr, &[7; 32]).await.unwrap();
+        assert!(resumptions.take(&addr, &[7; 32]).await.is_none());
+
+        resumptions.park([9; 32], slot).await;
+        resumptions.evict(&addr).await;
+        assert!(!resumptions.is_parked(&addr).await);
+        assert!(!channels.is_device_online(&addr).await);
+    }
+
+    #[tokio::test]
+    async fn take_over() {
+        let channels = UserChannels::new();
+        let resumptions = Resumptions::new();
+        let addr = DeviceAddr {
+            username: ShortIdStr::new("duskyelf").unwrap(),
+            device: 1,
+        };
+
+        let slot = Slot::open(addr.clone(), false, &channels).await;
+        resumptions.go_live([7; 32], &slot).await;
+
+        // Only a live device can be taken over, and only with its token
+        let other = DeviceAddr {
+            device: 2,
+            ..addr.clone()
+        };
+        assert!(!resumptions.take_over(&other, None, Duration::ZERO).await);
+        assert!(
+            !resumptions
+                .take_over(&addr, Some(&[8; 32]), Duration::ZERO)
+                .await
+        );
+
+        // A session that gives up its slot parks it
+        resumptions.leave(&addr, &[7; 32]).await;
+        resumptions.park([7; 32], slot).await;
+        assert!(!resumptions.take_over(&addr, None, Duration::ZERO).await);
+        assert!(resumptions.take(&addr, &[7; 32]).await.is_some());
+    }
+}
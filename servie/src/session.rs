@@ -0,0 +1,1883 @@
+use crate::{
+    queue::SendError, relay::RELAY_QUOTA, resume::Takeover, AppState, Blocklist, ChannelMsg,
+    ChannelMsgWithSender, Contacts, DeviceAddr, Mailbox, Result, SelfChannel, SerdeSocket,
+    ServieError, UserChannels,
+};
+
+use schemou::{legos::ShortIdStr, *};
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    time::Duration,
+};
+
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{interval_at, sleep_until, Instant, MissedTickBehavior},
+};
+
+/// How long a connection request waits for an answer before it's given up on
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connection requests a logged in device has in flight, in both directions
+///
+/// Every request has its own deadline, so one unanswered request never holds up the others
+pub struct Session {
+    /// Requests of our client, by the user they're sent to
+    outgoing: HashMap<ShortIdStr, Pending<RequestId>>,
+    /// Requests pushed to our client, by the id servie gave them
+    incoming: HashMap<RequestId, Pending<DeviceAddr>>,
+    /// Devices a connection was accepted with, by their user, the only ones allowed
+    /// to `Signal` us
+    peers: HashMap<ShortIdStr, DeviceId>,
+    /// Mails pushed to our client, they stay stored until acknowledged
+    delivered: HashSet<MailId>,
+    next_id: RequestId,
+    timeout: Duration,
+}
+
+struct Pending<T> {
+    value: T,
+    deadline: Instant,
+}
+
+/// A request that ran out of time
+#[derive(Debug, PartialEq, Eq)]
+pub enum Expired {
+    /// Our client's request `id` to `to` wasn't answered
+    Outgoing { id: RequestId, to: ShortIdStr },
+    /// Our client didn't answer `from`'s request
+    Incoming { from: DeviceAddr },
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new(REQUEST_TIMEOUT)
+    }
+}
+
+impl Session {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            peers: HashMap::new(),
+            delivered: HashSet::new(),
+            next_id: 0,
+            timeout,
+        }
+    }
+
+    pub fn has_outgoing(&self, to: &ShortIdStr) -> bool {
+        self.outgoing.contains_key(to)
+    }
+
+    /// Tracks our client's request `id` to `to`, fails if one to `to` is already pending
+    pub fn start_outgoing(&mut self, to: ShortIdStr, id: RequestId) -> bool {
+        if self.has_outgoing(&to) {
+            return false;
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        self.outgoing.insert(
+            to,
+            Pending {
+                value: id,
+                deadline,
+            },
+        );
+        true
+    }
+
+    /// Stops tracking the request to `to` as it got an answer, returns its id
+    pub fn resolve_outgoing(&mut self, to: &ShortIdStr) -> Option<RequestId> {
+        self.outgoing.remove(to).map(|pending| pending.value)
+    }
+
+    /// Tracks `from`'s request pushed to our client, returns the id to push it with
+    ///
+    /// `None` if a request from `from` is already pending
+    pub fn start_incoming(&mut self, from: DeviceAddr) -> Option<RequestId> {
+        if self.incoming.values().any(|pending| pending.value == from) {
+            return None;
+        }
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let deadline = Instant::now() + self.timeout;
+        self.incoming.insert(
+            id,
+            Pending {
+                value: from,
+                deadline,
+            },
+        );
+        Some(id)
+    }
+
+    /// Stops tracking incoming request `id` as our client answered it, returns who sent it
+    ///
+    /// `None` if there is no such request, eg. it already expired
+    pub fn resolve_incoming(&mut self, id: RequestId) -> Option<DeviceAddr> {
+        self.incoming.remove(&id).map(|pending| pending.value)
+    }
+
+    /// Stops tracking our client's request `id` as it was cancelled, returns who it was sent to
+    pub fn cancel_outgoing(&mut self, id: RequestId) -> Option<ShortIdStr> {
+        let to = self
+            .outgoing
+            .iter()
+            .find(|(_, pending)| pending.value == id)
+            .map(|(to, _)| to.clone())?;
+
+        self.outgoing.remove(&to);
+        Some(to)
+    }
+
+    /// Stops tracking the request from `from`, eg. when they cancelled it,
+    /// returns the id it was pushed with
+    pub fn take_incoming(&mut self, from: &DeviceAddr) -> Option<RequestId> {
+        let id = self
+            .incoming
+            .iter()
+            .find(|(_, pending)| &pending.value == from)
+            .map(|(id, _)| *id)?;
+
+        self.incoming.remove(&id);
+        Some(id)
+    }
+
+    /// Stops tracking a request from any device of `from`, eg. when our client asked
+    /// them too, returns the device it came from
+    pub fn take_incoming_from_user(&mut self, from: &ShortIdStr) -> Option<DeviceAddr> {
+        let id = self
+            .incoming
+            .iter()
+            .find(|(_, pending)| &pending.value.username == from)
+            .map(|(id, _)| *id)?;
+
+        self.incoming.remove(&id).map(|pending| pending.value)
+    }
+
+    /// Replaces any earlier connection with the same user
+    pub fn add_peer(&mut self, peer: DeviceAddr) {
+        self.peers.insert(peer.username, peer.device);
+    }
+
+    /// Forgets `peer`, returns `false` if it wasn't our peer
+    pub fn remove_peer(&mut self, peer: &DeviceAddr) -> bool {
+        let is_peer = self.is_peer(peer);
+        if is_peer {
+            self.peers.remove(&peer.username);
+        }
+        is_peer
+    }
+
+    pub fn is_peer(&self, device: &DeviceAddr) -> bool {
+        self.peers.get(&device.username) == Some(&device.device)
+    }
+
+    /// The device of `user` we have an accepted connection with
+    pub fn peer(&self, user: &ShortIdStr) -> Option<DeviceAddr> {
+        self.peers.get(user).map(|device| DeviceAddr {
+            username: user.clone(),
+            device: *device,
+        })
+    }
+
+    /// Records that mail `id` was pushed to our client, returns `false` if it already was
+    pub fn mark_delivered(&mut self, id: MailId) -> bool {
+        self.delivered.insert(id)
+    }
+
+    /// Pushes to a previous socket might have been lost with it, so unacknowledged
+    /// mail is pushed again, returns the pending requests to push again
+    pub fn resume(&mut self) -> Vec<ConnectToUser> {
+        self.delivered.clear();
+        self.incoming
+            .iter()
+            .map(|(id, pending)| ConnectToUser {
+                id: *id,
+                username: pending.value.username.clone(),
+            })
+            .collect()
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let outgoing = self.outgoing.values().map(|pending| pending.deadline);
+        let incoming = self.incoming.values().map(|pending| pending.deadline);
+        outgoing.chain(incoming).min()
+    }
+
+    /// Removes and returns every request whose deadline is at or before `now`
+    pub fn expire(&mut self, now: Instant) -> Vec<Expired> {
+        let mut expired = Vec::new();
+
+        self.outgoing.retain(|to, pending| {
+            let alive = pending.deadline > now;
+            if !alive {
+                expired.push(Expired::Outgoing {
+                    id: pending.value,
+                    to: to.clone(),
+                });
+            }
+            alive
+        });
+
+        self.incoming.retain(|_, pending| {
+            let alive = pending.deadline > now;
+            if !alive {
+                expired.push(Expired::Incoming {
+                    from: pending.value.clone(),
+                });
+            }
+            alive
+        });
+
+        expired
+    }
+
+    /// Waits for the next deadline and returns the requests that expired
+    ///
+    /// Never resolves while nothing is pending, cancel safe
+    pub async fn expired(&mut self) -> Vec<Expired> {
+        match self.next_deadline() {
+            Some(deadline) => {
+                sleep_until(deadline).await;
+                self.expire(Instant::now())
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Pushes the stored mail of `username` that wasn't pushed in this session yet
+async fn deliver_mail(
+    socket: &mut impl SerdeSocket,
+    mailbox: &Mailbox,
+    username: &ShortIdStr,
+    session: &mut Session,
+) -> Result<()> {
+    for mail in mailbox.pending(username).await? {
+        if session.mark_delivered(mail.id) {
+            tracing::debug!(mail_id = mail.id, "delivering mail");
+            socket.send_se(S2C::from(mail)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Tells the devices of `to` other than `answered`, which settled our request,
+/// that it was handled elsewhere
+async fn settled_elsewhere(
+    user_channels: &UserChannels,
+    me: &DeviceAddr,
+    to: &ShortIdStr,
+    answered: DeviceId,
+) {
+    if let Some(other) = user_channels.get(to).await {
+        other
+            .tell_others(answered, me, ChannelMsg::ConnectHandledElsewhere)
+            .await;
+    }
+}
+
+/// Whether `watcher` may see if `user` is online, users blocked by `user`, and users who
+/// aren't contacts of `user` in contacts-only mode, see them offline
+async fn shows_presence(
+    blocklist: &Blocklist,
+    contacts: &Contacts,
+    user: &ShortIdStr,
+    watcher: &ShortIdStr,
+) -> Result<bool> {
+    Ok(!blocklist.is_blocked(user, watcher).await? && contacts.accepts(user, watcher).await?)
+}
+
+/// A stored user list as sent on the wire, `UserList` keeps lists within the same maximum
+fn bounded<const MAX: usize>(
+    usernames: Vec<ShortIdStr>,
+) -> Result<legos::BoundedVec<ShortIdStr, MAX>> {
+    legos::BoundedVec::new(usernames).map_err(|err| io::Error::other(err.to_string()).into())
+}
+
+/// A logged in device, kept online across reconnects by `Resumptions`
+pub struct Slot {
+    me: DeviceAddr,
+    self_channel: SelfChannel,
+    session: Session,
+    /// New connections of the same device asking for the slot, see `Resumptions::take_over`
+    takeovers: mpsc::Receiver<Takeover>,
+    takeovers_tx: mpsc::Sender<Takeover>,
+    /// Told once the slot is parked, if it was taken over
+    taken_over: Option<oneshot::Sender<()>>,
+}
+
+impl Slot {
+    /// Goes online as `me`, hidden from presence subscribers if `hide_presence`
+    pub async fn open(me: DeviceAddr, hide_presence: bool, user_channels: &UserChannels) -> Self {
+        let (takeovers_tx, takeovers) = mpsc::channel(1);
+        Self {
+            self_channel: SelfChannel::new(me.clone(), user_channels.clone(), hide_presence).await,
+            session: Session::default(),
+            me,
+            takeovers,
+            takeovers_tx,
+            taken_over: None,
+        }
+    }
+
+    pub fn addr(&self) -> &DeviceAddr {
+        &self.me
+    }
+
+    pub(crate) fn takeovers(&self) -> mpsc::Sender<Takeover> {
+        self.takeovers_tx.clone()
+    }
+
+    pub(crate) fn taken_over(&mut self) -> Option<oneshot::Sender<()>> {
+        self.taken_over.take()
+    }
+
+    /// Goes offline, returns once the device is gone from `UserChannels`
+    pub async fn close(self) {
+        self.self_channel.close().await;
+    }
+}
+
+/// Serves a logged in device until the socket closes or the user violates the protocol
+///
+/// `slot` is either fresh or resumed, and can be parked again if the socket closed
+pub async fn run(socket: &mut impl SerdeSocket, slot: &mut Slot, state: &AppState) -> Result<()> {
+    let AppState {
+        mirror,
+        user_channels,
+        mailbox,
+        blocklist,
+        contacts,
+        relay,
+        heartbeat,
+        ..
+    } = state;
+    let Slot {
+        me,
+        self_channel,
+        session,
+        takeovers,
+        taken_over,
+        ..
+    } = slot;
+    let me = &*me;
+    let username = me.username.clone();
+
+    for request in session.resume() {
+        socket.send_se(S2C::from(request)).await?;
+    }
+
+    // Mail that arrived while the user was offline
+    deliver_mail(socket, mailbox, &username, session).await?;
+
+    // Contact requests are pushed again until answered, like mail until acked
+    for from in contacts.pending_requests(&username).await? {
+        socket
+            .send_se(S2C::from(S2CContactRequest { username: from }))
+            .await?;
+    }
+
+    let mut pings = interval_at(Instant::now() + heartbeat.interval, heartbeat.interval);
+    pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            ws_recv = socket.recv_de() => match ws_recv? {
+                C2S::ConnectToUser(ConnectToUser { id, username: other_username }) => {
+                    let result = 'result: {
+                        if session.has_outgoing(&other_username) {
+                            break 'result Some(S2CConnectToUserResult::UserBusy);
+                        }
+
+                        // TODO: Ban IPs in case of invalid username
+                        // Issue URL: https://github.com/Colabie/Colabie/issues/74
+                        // labels: enhancement, discussion
+                        let Some(other) = user_channels.get(&other_username).await else {
+                            break 'result Some(S2CConnectToUserResult::Offline);
+                        };
+
+                        // Implicitly accept if the other user is already trying to connect to us,
+                        // their device settles it for our other devices
+                        if let Some(from) = session.take_incoming_from_user(&other_username) {
+                            if let Some(device) = other.device(from.device) {
+                                _ = device.tell(me, ChannelMsg::ConnectToUserAccept).await;
+                            }
+                            session.add_peer(from);
+                            break 'result Some(S2CConnectToUserResult::Accept);
+                        }
+
+                        // Blocked users, and users who aren't contacts of a user in contacts-only mode,
+                        // can't tell being refused from the user being offline
+                        if blocklist.is_blocked(&other_username, &username).await?
+                            || !contacts.accepts(&other_username, &username).await?
+                        {
+                            tracing::debug!(to = *other_username, "connection request refused by the user's privacy settings");
+                            break 'result Some(S2CConnectToUserResult::Offline);
+                        }
+
+                        match self_channel.request_connect(&other_username, &other).await {
+                            Ok(()) => {
+                                session.start_outgoing(other_username.clone(), id);
+                                None
+                            }
+                            Err(SendError::Full) => Some(S2CConnectToUserResult::MailboxFull),
+                            // Every device went offline meanwhile
+                            Err(SendError::Closed) => Some(S2CConnectToUserResult::Offline),
+                        }
+                    };
+
+                    if let Some(result) = result {
+                        tracing::debug!(to = *other_username, id, ?result, "connection request resolved");
+                        socket.send_se(S2C::from(S2CConnectToUserReply { id, result })).await?;
+                    }
+                }
+
+                C2S::ConnectToUserResult(C2SConnectToUserReply { id, result }) => {
+                    // The request might have expired while the user was deciding
+                    let Some(from) = session.resolve_incoming(id) else {
+                        tracing::debug!(id, "reply to an unknown or expired request");
+                        continue;
+                    };
+
+                    let Some(other) = user_channels.get_device(&from).await else {
+                        continue;
+                    };
+
+                    // The requesting device has the last word, it tells us if another device
+                    // of ours answered first
+                    let message = match result {
+                        C2SConnectToUserResult::Reject => ChannelMsg::ConnectToUserReject,
+                        C2SConnectToUserResult::Accept => {
+                            session.add_peer(from.clone());
+                            ChannelMsg::ConnectToUserAccept
+                        }
+                    };
+                    if other.tell(me, message).await.is_err() {
+                        tracing::warn!(to = *from.username, "failed to deliver the answer to a connection request");
+                    }
+                }
+
+                C2S::CancelConnect(C2SCancelConnect { id }) => {
+                    // The request might have been answered in the meantime
+                    let Some(to) = session.cancel_outgoing(id) else {
+                        tracing::debug!(id, "cancel of an unknown or settled request");
+                        continue;
+                    };
+                    self_channel.settle(&to);
+
+                    if let Some(other) = user_channels.get(&to).await {
+                        _ = other.tell_all(me, ChannelMsg::ConnectToUserCancel).await;
+                    }
+
+                    tracing::debug!(to = *to, id, "connection request cancelled");
+                    socket.send_se(S2C::from(S2CConnectToUserReply {
+                        id,
+                        result: S2CConnectToUserResult::Cancelled,
+                    })).await?;
+                }
+
+                C2S::Signal(C2SSignal { to, signal }) => {
+                    let Some(peer) = session.peer(&to) else {
+                        tracing::warn!(to = *to, "dropping signal to a user without an accepted connection");
+                        continue;
+                    };
+
+                    let Some(other) = user_channels.get_device(&peer).await else {
+                        continue;
+                    };
+
+                    // Waiting for room would stall this session, pings included, on a stuck peer,
+                    // the peers renegotiate if a signal is lost
+                    if other.try_tell(me, ChannelMsg::Signal(signal)).is_err() {
+                        tracing::warn!(to = *to, "dropped a signal, peer isn't keeping up");
+                    }
+                }
+
+                C2S::RelayFrame(C2SRelayFrame { to, frame }) => {
+                    let Some(peer) = session.peer(&to) else {
+                        tracing::warn!(to = *to, "dropping frame to a user without an accepted connection");
+                        continue;
+                    };
+
+                    let len = frame.len();
+                    if !relay.charge(&username, len).await {
+                        return Err(ServieError::RelayQuotaExceeded(RELAY_QUOTA));
+                    }
+
+                    let Some(other) = user_channels.get_device(&peer).await else {
+                        continue;
+                    };
+
+                    // Like signals, frames are dropped rather than waited on
+                    match other.try_tell(me, ChannelMsg::RelayFrame(frame)) {
+                        Ok(()) => tracing::trace!(to = *to, len, "relayed frame"),
+                        _ => tracing::warn!(to = *to, len, "dropped a frame, peer isn't keeping up"),
+                    }
+                }
+
+                C2S::SendMail(C2SSendMail { id, to, envelope }) => {
+                    // Served from the mirror as is, a miss must not let anyone force a refresh,
+                    // users who just registered are picked up by the periodic one
+                    let result = match mirror.clone().lookup_record(to.clone()).await {
+                        None => S2CSendMailResult::UnknownUser,
+                        // Like connection requests, they can't tell being blocked from being read
+                        Some(_) if blocklist.is_blocked(&to, &username).await? => {
+                            tracing::debug!(to = *to, "dropping mail to a user who blocks the sender");
+                            S2CSendMailResult::Stored
+                        }
+                        Some(_) => match mailbox.store(username.clone(), &to, envelope).await? {
+                            Some(mail_id) => {
+                                tracing::debug!(to = *to, mail_id, "stored mail");
+                                if let Some(other) = user_channels.get(&to).await {
+                                    _ = other.tell_all(me, ChannelMsg::MailArrived).await;
+                                }
+                                S2CSendMailResult::Stored
+                            }
+                            None => S2CSendMailResult::MailboxFull,
+                        },
+                    };
+
+                    socket.send_se(S2C::from(S2CSendMailReply { id, result })).await?;
+                }
+
+                C2S::MailAck(C2SMailAck { id }) => {
+                    if !mailbox.ack(&username, id).await? {
+                        tracing::debug!(mail_id = id, "ack of an unknown mail");
+                    }
+                }
+
+                C2S::SubscribePresence(C2SSubscribePresence { usernames }) => {
+                    let presence = self_channel.subscribe(usernames.into_iter().collect()).await;
+                    tracing::debug!(subscriptions = presence.len(), "presence subscriptions replaced");
+                    for (other, online) in presence {
+                        let online = online && shows_presence(blocklist, contacts, &other, &username).await?;
+                        socket.send_se(S2C::from(S2CPresence { username: other, online })).await?;
+                    }
+                }
+
+                C2S::SetPresence(C2SSetPresence { hidden }) => {
+                    tracing::debug!(hidden, "presence visibility changed");
+                    self_channel.set_hidden(hidden).await;
+                }
+
+                C2S::SetBlocked(C2SSetBlocked { id, username: other, blocked }) => {
+                    let result = match blocklist.set_blocked(&username, other.clone(), blocked).await? {
+                        true => {
+                            tracing::debug!(user = *other, blocked, "block list changed");
+                            if blocked {
+                                contacts.dismiss_request(&username, other.clone()).await?;
+                            }
+                            self_channel.reannounce(Some(&other)).await;
+                            S2CSetBlockedResult::Done
+                        }
+                        false => S2CSetBlockedResult::ListFull,
+                    };
+                    socket.send_se(S2C::from(S2CSetBlockedReply { id, result })).await?;
+                }
+
+                C2S::GetBlockList(C2SGetBlockList { id }) => {
+                    let usernames = bounded(blocklist.blocked(&username).await?)?;
+                    socket.send_se(S2C::from(S2CBlockList { id, usernames })).await?;
+                }
+
+                C2S::SetContactsOnly(C2SSetContactsOnly { enabled }) => {
+                    tracing::debug!(enabled, "contacts-only mode changed");
+                    contacts.set_contacts_only(&username, enabled).await?;
+                    self_channel.reannounce(None).await;
+                }
+
+                C2S::SetContact(C2SSetContact { id, username: other, contact }) => {
+                    let result = match contacts.set_contact(&username, other.clone(), contact).await? {
+                        true => {
+                            tracing::debug!(user = *other, contact, "contacts changed");
+                            self_channel.reannounce(Some(&other)).await;
+                            S2CSetContactResult::Done
+                        }
+                        false => S2CSetContactResult::ListFull,
+                    };
+                    socket.send_se(S2C::from(S2CSetContactReply { id, result })).await?;
+                }
+
+                C2S::GetContacts(C2SGetContacts { id }) => {
+                    let contacts_only = contacts.is_contacts_only(&username).await?;
+                    let usernames = bounded(contacts.contacts(&username).await?)?;
+                    socket.send_se(S2C::from(S2CContacts { id, contacts_only, usernames })).await?;
+                }
+
+                C2S::ContactRequest(C2SContactRequest { id, username: other_username }) => {
+                    let result = 'result: {
+                        // Charged even if refused, so the limit also bounds probing
+                        if !contacts.charge_request(&username).await {
+                            break 'result S2CContactRequestResult::RateLimited;
+                        }
+
+                        // Stored whether the user is online, hidden or in contacts-only mode, so the
+                        // answer is the same, and dropped if they block the sender or don't exist
+                        if mirror.clone().lookup_record(other_username.clone()).await.is_none() {
+                            break 'result S2CContactRequestResult::Sent;
+                        }
+                        if blocklist.is_blocked(&other_username, &username).await? {
+                            tracing::debug!(to = *other_username, "dropping contact request to a user who blocks the sender");
+                            break 'result S2CContactRequestResult::Sent;
+                        }
+
+                        contacts.store_request(&other_username, username.clone()).await?;
+                        if let Some(other) = user_channels.get(&other_username).await {
+                            // Pushed again on their next login if this doesn't get through
+                            _ = other.tell_all(me, ChannelMsg::ContactRequest).await;
+                        }
+                        S2CContactRequestResult::Sent
+                    };
+
+                    tracing::debug!(to = *other_username, id, ?result, "contact request");
+                    socket.send_se(S2C::from(S2CContactRequestReply { id, result })).await?;
+                }
+
+                _ => return Err(ServieError::NonCompliance("Unexpected message after authentication")),
+            },
+
+            ChannelMsgWithSender { from, message } = self_channel.hear() => {
+                let result = match message {
+                    ChannelMsg::ConnectToUser => {
+                        // Implicitly accept if we are also trying to connect to them
+                        if session.has_outgoing(&from.username) {
+                            if let Some(other) = user_channels.get_device(&from).await {
+                                _ = other.tell(me, ChannelMsg::ConnectToUserAccept).await;
+                            }
+                            S2CConnectToUserResult::Accept
+                        } else {
+                            if let Some(id) = session.start_incoming(from.clone()) {
+                                socket.send_se(S2C::from(ConnectToUser { id, username: from.username })).await?;
+                            }
+                            continue;
+                        }
+                    }
+
+                    ChannelMsg::ConnectToUserAccept => S2CConnectToUserResult::Accept,
+                    ChannelMsg::ConnectToUserReject => S2CConnectToUserResult::Reject,
+                    ChannelMsg::UserBusy => S2CConnectToUserResult::UserBusy,
+
+                    // Both ends check, the sender's servie might not know the connection expired
+                    ChannelMsg::Signal(signal) => {
+                        match session.is_peer(&from) {
+                            true => socket.send_se(S2C::from(S2CSignal { from: from.username, signal })).await?,
+                            false => tracing::debug!(from = *from.username, "dropping signal from a device without an accepted connection"),
+                        }
+                        continue;
+                    }
+
+                    ChannelMsg::RelayFrame(frame) => {
+                        match session.is_peer(&from) {
+                            true => socket.send_se(S2C::from(S2CRelayFrame { from: from.username, frame })).await?,
+                            false => tracing::debug!(from = *from.username, "dropping frame from a device without an accepted connection"),
+                        }
+                        continue;
+                    }
+
+                    ChannelMsg::MailArrived => {
+                        deliver_mail(socket, mailbox, &username, session).await?;
+                        continue;
+                    }
+
+                    ChannelMsg::ContactRequest => {
+                        socket.send_se(S2C::from(S2CContactRequest { username: from.username })).await?;
+                        continue;
+                    }
+
+                    // Might have been sent right before we unsubscribed
+                    ChannelMsg::Presence { online } => {
+                        if self_channel.is_subscribed(&from.username) {
+                            let online = online && shows_presence(blocklist, contacts, &from.username, &username).await?;
+                            socket.send_se(S2C::from(S2CPresence { username: from.username, online })).await?;
+                        }
+                        continue;
+                    }
+
+                    ChannelMsg::ConnectToUserCancel => {
+                        if let Some(id) = session.take_incoming(&from) {
+                            tracing::debug!(from = *from.username, "connection request withdrawn");
+                            socket.send_se(S2C::from(S2CConnectCancelled { id, username: from.username })).await?;
+                        }
+                        continue;
+                    }
+
+                    // Either still pending here, or we answered it but lost the race
+                    ChannelMsg::ConnectHandledElsewhere => {
+                        let pending = session.take_incoming(&from).is_some();
+                        if pending || session.remove_peer(&from) {
+                            tracing::debug!(from = *from.username, "connection request handled on another device");
+                            socket.send_se(S2C::from(S2CConnectHandledElsewhere { username: from.username })).await?;
+                        }
+                        continue;
+                    }
+                };
+
+                // The first device to answer settles the request, answers to requests
+                // that already settled or expired are void
+                match session.resolve_outgoing(&from.username) {
+                    Some(id) => {
+                        self_channel.settle(&from.username);
+                        settled_elsewhere(user_channels, me, &from.username, from.device).await;
+                        if result == S2CConnectToUserResult::Accept {
+                            session.add_peer(from.clone());
+                        }
+                        tracing::debug!(to = *from.username, id, ?result, "connection request resolved");
+                        socket.send_se(S2C::from(S2CConnectToUserReply { id, result })).await?;
+                    }
+                    None if !session.is_peer(&from) => {
+                        if let Some(other) = user_channels.get_device(&from).await {
+                            _ = other.tell(me, ChannelMsg::ConnectHandledElsewhere).await;
+                        }
+                    }
+                    None => {}
+                }
+            }
+
+            // A half-open connection would otherwise keep the device online forever
+            _ = pings.tick() => {
+                let silent = socket.last_seen().elapsed();
+                if silent > heartbeat.timeout {
+                    tracing::info!(silent = ?silent, "reaping an unresponsive connection");
+                    return Err(ServieError::Unresponsive(silent));
+                }
+                socket.ping().await?;
+            }
+
+            // The device reconnected, maybe before we noticed this socket is dead
+            Some(takeover) = takeovers.recv() => {
+                let silent = socket.last_seen().elapsed();
+                if silent < takeover.min_silence {
+                    tracing::debug!(silent = ?silent, "refusing to hand a responsive connection over");
+                    continue;
+                }
+
+                tracing::info!(silent = ?silent, "connection taken over by the device reconnecting");
+                *taken_over = Some(takeover.parked);
+                return Err(ServieError::TakenOver);
+            }
+
+            expired = session.expired() => {
+                for expired in expired {
+                    match expired {
+                        Expired::Outgoing { id, to } => {
+                            tracing::debug!(to = *to, id, "connection request timed out");
+                            self_channel.settle(&to);
+                            if let Some(other) = user_channels.get(&to).await {
+                                _ = other.tell_all(me, ChannelMsg::ConnectToUserCancel).await;
+                            }
+
+                            socket.send_se(S2C::from(S2CConnectToUserReply {
+                                id,
+                                result: S2CConnectToUserResult::Timeout,
+                            })).await?;
+                        }
+                        Expired::Incoming { from } => {
+                            tracing::debug!(from = *from.username, "connection request went unanswered");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod session_tests {
+    use super::{run, Expired, Session, Slot, RELAY_QUOTA};
+    use crate::{
+        test_utils::TestEnv, AppState, DeviceAddr, Heartbeat, Overflow, QueueConfig, Result,
+        SerdeSocket, ServieError, UserChannels,
+    };
+
+    use schemou::{legos::ShortIdStr, *};
+
+    use std::{fmt, time::Duration};
+
+    use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+
+    /// In-memory stand-in for a WebSocket, one end is served by `run`,
+    /// the other is used as a native clientie
+    struct TestSocket {
+        rx: mpsc::UnboundedReceiver<Vec<u8>>,
+        tx: mpsc::UnboundedSender<Vec<u8>>,
+        last_seen: Instant,
+    }
+
+    impl TestSocket {
+        fn pair() -> (Self, Self) {
+            let (client_tx, server_rx) = mpsc::unbounded_channel();
+            let (server_tx, client_rx) = mpsc::unbounded_channel();
+
+            let server = TestSocket {
+                rx: server_rx,
+                tx: server_tx,
+                last_seen: Instant::now(),
+            };
+            let client = TestSocket {
+                rx: client_rx,
+                tx: client_tx,
+                last_seen: Instant::now(),
+            };
+            (server, client)
+        }
+    }
+
+    impl SerdeSocket for TestSocket {
+        async fn recv_de<T: Sirius + fmt::Debug>(&mut self) -> Result<T> {
+            let data = self.rx.recv().await.ok_or(ServieError::SocketClosed)?;
+            self.last_seen = Instant::now();
+            Ok(schemou::decode(&data)?)
+        }
+
+        async fn send_se<T: Sirius + fmt::Debug>(&mut self, data: T) -> Result<()> {
+            self.tx
+                .send(data.serialize_buffered())
+                .map_err(|_| ServieError::SocketClosed)
+        }
+
+        // Nothing answers pings in memory, only messages keep a connection alive
+        async fn ping(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn last_seen(&self) -> Instant {
+            self.last_seen
+        }
+    }
+
+    /// Serves `username` as if they just logged in, returns the client's end of the socket
+    async fn login(username: &ShortIdStr, state: &AppState) -> TestSocket {
+        login_device(username, 0, state).await
+    }
+
+    /// `login` from a specific device
+    async fn login_device(username: &ShortIdStr, device: DeviceId, state: &AppState) -> TestSocket {
+        let addr = DeviceAddr {
+            username: username.clone(),
+            device,
+        };
+
+        let slot = Slot::open(addr, false, &state.user_channels).await;
+        serve(slot, state).0
+    }
+
+    /// Serves `slot` over a fresh socket, returns the client's end and the slot
+    /// once the socket closes
+    fn serve(mut slot: Slot, state: &AppState) -> (TestSocket, JoinHandle<Slot>) {
+        let (mut server, client) = TestSocket::pair();
+        let handle = tokio::spawn({
+            let state = state.clone();
+            async move {
+                _ = run(&mut server, &mut slot, &state).await;
+                slot
+            }
+        });
+
+        (client, handle)
+    }
+
+    fn device(username: &ShortIdStr, device: DeviceId) -> DeviceAddr {
+        DeviceAddr {
+            username: username.clone(),
+            device,
+        }
+    }
+
+    #[test]
+    fn concurrent_requests() {
+        let mut session = Session::new(Duration::from_secs(10));
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+        let laptop = device(&duskyelf, 1);
+
+        assert!(session.start_outgoing(duskyelf.clone(), 1));
+        assert!(!session.start_outgoing(duskyelf.clone(), 2));
+        assert!(session.start_outgoing(thatmagicalcat.clone(), 3));
+
+        let incoming = session.start_incoming(laptop.clone()).unwrap();
+        assert!(session.start_incoming(laptop.clone()).is_none());
+        assert!(session.start_incoming(device(&duskyelf, 2)).is_some());
+
+        assert_eq!(session.resolve_outgoing(&thatmagicalcat), Some(3));
+        assert_eq!(session.resolve_outgoing(&thatmagicalcat), None);
+        assert_eq!(session.resolve_incoming(incoming), Some(laptop));
+        assert_eq!(session.resolve_incoming(incoming), None);
+        assert!(session.has_outgoing(&duskyelf));
+    }
+
+    #[test]
+    fn cancel() {
+        let mut session = Session::new(Duration::from_secs(10));
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+
+        assert!(session.start_outgoing(duskyelf.clone(), 1));
+        assert_eq!(session.cancel_outgoing(2), None);
+        assert_eq!(session.cancel_outgoing(1), Some(duskyelf.clone()));
+        assert!(!session.has_outgoing(&duskyelf));
+
+        let laptop = device(&duskyelf, 1);
+        let incoming = session.start_incoming(laptop.clone()).unwrap();
+        assert_eq!(session.take_incoming(&device(&duskyelf, 2)), None);
+        assert_eq!(session.take_incoming(&laptop), Some(incoming));
+        assert_eq!(session.resolve_incoming(incoming), None);
+
+        session.start_incoming(laptop.clone()).unwrap();
+        assert_eq!(session.take_incoming_from_user(&duskyelf), Some(laptop));
+    }
+
+    #[test]
+    fn timeouts() {
+        let mut session = Session::new(Duration::from_secs(10));
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        assert!(session.next_deadline().is_none());
+        assert!(session.start_outgoing(duskyelf.clone(), 1));
+        session.start_incoming(device(&thatmagicalcat, 1)).unwrap();
+
+        let deadline = session.next_deadline().unwrap();
+        assert!(session.expire(Instant::now()).is_empty());
+
+        let mut expired = session.expire(deadline + Duration::from_secs(1));
+        expired.sort_by_key(|expired| matches!(expired, Expired::Incoming { .. }));
+        assert_eq!(
+            expired,
+            [
+                Expired::Outgoing {
+                    id: 1,
+                    to: duskyelf
+                },
+                Expired::Incoming {
+                    from: device(&thatmagicalcat, 1)
+                },
+            ]
+        );
+        assert!(session.next_deadline().is_none());
+    }
+
+    /// Makes `caller` connect to `callee`, who accepts
+    async fn connect(
+        caller: &mut TestSocket,
+        callee: &mut TestSocket,
+        caller_name: &ShortIdStr,
+        callee_name: &ShortIdStr,
+    ) {
+        caller
+            .send_se(C2S::from(ConnectToUser {
+                id: 7,
+                username: callee_name.clone(),
+            }))
+            .await
+            .unwrap();
+
+        let S2C::ConnectToUser(ConnectToUser { id, username }) = callee.recv_de().await.unwrap()
+        else {
+            panic!("Expected ConnectToUser");
+        };
+        assert_eq!(&username, caller_name);
+
+        callee
+            .send_se(C2S::from(C2SConnectToUserReply {
+                id,
+                result: C2SConnectToUserResult::Accept,
+            }))
+            .await
+            .unwrap();
+
+        let S2C::ConnectToUserResult(reply) = caller.recv_de().await.unwrap() else {
+            panic!("Expected S2CConnectToUserReply");
+        };
+        assert_eq!(reply.id, 7);
+        assert_eq!(reply.result, S2CConnectToUserResult::Accept);
+    }
+
+    fn signal_bytes(signal: &Signal) -> &[u8] {
+        match signal {
+            Signal::Offer(sdp) | Signal::Answer(sdp) | Signal::IceCandidate(sdp) => sdp,
+        }
+    }
+
+    #[tokio::test]
+    async fn signal_relay() {
+        let env = TestEnv::new().await;
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+        let stranger = ShortIdStr::new("stranger").unwrap();
+
+        let mut caller = login(&duskyelf, &env.state).await;
+        let mut callee = login(&thatmagicalcat, &env.state).await;
+        let mut intruder = login(&stranger, &env.state).await;
+
+        connect(&mut caller, &mut callee, &duskyelf, &thatmagicalcat).await;
+
+        let offer = b"v=0\r\no=- 1 1 IN IP4 127.0.0.1\r\ns=-\r\n";
+        caller
+            .send_se(C2S::from(C2SSignal {
+                to: thatmagicalcat.clone(),
+                signal: Signal::Offer(legos::BoundedBytes::new(offer.as_slice()).unwrap()),
+            }))
+            .await
+            .unwrap();
+
+        let S2C::Signal(S2CSignal { from, signal }) = callee.recv_de().await.unwrap() else {
+            panic!("Expected S2CSignal");
+        };
+        assert_eq!(from, duskyelf);
+        assert!(matches!(signal, Signal::Offer(_)));
+        assert_eq!(signal_bytes(&signal), offer);
+
+        // Not an accepted peer, servie drops it
+        intruder
+            .send_se(C2S::from(C2SSignal {
+                to: duskyelf.clone(),
+                signal: Signal::Answer(legos::BoundedBytes::new(b"v=0".as_slice()).unwrap()),
+            }))
+            .await
+            .unwrap();
+
+        let candidate = br#"{"candidate":"candidate:1 1 udp 1 127.0.0.1 9 typ host"}"#;
+        callee
+            .send_se(C2S::from(C2SSignal {
+                to: duskyelf.clone(),
+                signal: Signal::IceCandidate(
+                    legos::BoundedBytes::new(candidate.as_slice()).unwrap(),
+                ),
+            }))
+            .await
+            .unwrap();
+
+        let S2C::Signal(S2CSignal { from, signal }) = caller.recv_de().await.unwrap() else {
+            panic!("Expected S2CSignal");
+        };
+        assert_eq!(from, thatmagicalcat);
+        assert_eq!(signal_bytes(&signal), candidate);
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn frame_relay() {
+        let env = TestEnv::new().await;
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        let mut caller = login(&duskyelf, &env.state).await;
+        let mut callee = login(&thatmagicalcat, &env.state).await;
+        connect(&mut caller, &mut callee, &duskyelf, &thatmagicalcat).await;
+
+        let ciphertext = [0xc0, 0x1a, 0xb1, 0xe0];
+        caller
+            .send_se(C2S::from(C2SRelayFrame {
+                to: thatmagicalcat.clone(),
+                frame: legos::BoundedBytes::new(ciphertext.as_slice()).unwrap(),
+            }))
+            .await
+            .unwrap();
+
+        let S2C::RelayFrame(S2CRelayFrame { from, frame }) = callee.recv_de().await.unwrap() else {
+            panic!("Expected S2CRelayFrame");
+        };
+        assert_eq!(from, duskyelf);
+        assert_eq!(&*frame, ciphertext.as_slice());
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn relay_quota() {
+        let env = TestEnv::new().await;
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+        let ciphertext = [0xc0, 0x1a, 0xb1, 0xe0];
+        let frame = || {
+            C2S::from(C2SRelayFrame {
+                to: thatmagicalcat.clone(),
+                frame: legos::BoundedBytes::new(ciphertext.as_slice()).unwrap(),
+            })
+        };
+
+        // Room for exactly one more frame
+        let used = RELAY_QUOTA as usize - ciphertext.len();
+        assert!(env.state.relay.charge(&duskyelf, used).await);
+
+        let mut callee = login(&thatmagicalcat, &env.state).await;
+        let mut caller = login(&duskyelf, &env.state).await;
+        connect(&mut caller, &mut callee, &duskyelf, &thatmagicalcat).await;
+
+        caller.send_se(frame()).await.unwrap();
+        let S2C::RelayFrame(_) = callee.recv_de().await.unwrap() else {
+            panic!("Expected S2CRelayFrame");
+        };
+
+        caller.send_se(frame()).await.unwrap();
+        assert!(matches!(
+            caller.recv_de::<S2C>().await,
+            Err(ServieError::SocketClosed)
+        ));
+
+        // Reconnecting doesn't reset the quota
+        let mut caller = login(&duskyelf, &env.state).await;
+        connect(&mut caller, &mut callee, &duskyelf, &thatmagicalcat).await;
+        caller.send_se(frame()).await.unwrap();
+        assert!(matches!(
+            caller.recv_de::<S2C>().await,
+            Err(ServieError::SocketClosed)
+        ));
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn mail_delivery() {
+        let env = TestEnv::new().await;
+        let duskyelf = env.register("duskyelf").await;
+        let thatmagicalcat = env.register("thatmagicalcat").await;
+
+        let mut sender = login(&duskyelf, &env.state).await;
+
+        for (id, to, expected) in [
+            (1, thatmagicalcat.clone(), S2CSendMailResult::Stored),
+            (
+                2,
+                ShortIdStr::new("nobody").unwrap(),
+                S2CSendMailResult::UnknownUser,
+            ),
+        ] {
+            sender
+                .send_se(C2S::from(C2SSendMail {
+                    id,
+                    to,
+                    envelope: MailEnvelope::new([1, 2, 3].as_slice()).unwrap(),
+                }))
+                .await
+                .unwrap();
+
+            let S2C::SendMailResult(reply) = sender.recv_de().await.unwrap() else {
+                panic!("Expected S2CSendMailReply");
+            };
+            assert_eq!(reply.id, id);
+            assert_eq!(reply.result, expected);
+        }
+
+        // Delivered after login
+        let mut recipient = login(&thatmagicalcat, &env.state).await;
+        let S2C::Mail(mail) = recipient.recv_de().await.unwrap() else {
+            panic!("Expected S2CMail");
+        };
+        assert_eq!(mail.from, duskyelf);
+        assert_eq!(&*mail.envelope, [1, 2, 3].as_slice());
+        recipient
+            .send_se(C2S::from(C2SMailAck { id: mail.id }))
+            .await
+            .unwrap();
+
+        // Delivered right away while online
+        sender
+            .send_se(C2S::from(C2SSendMail {
+                id: 3,
+                to: thatmagicalcat.clone(),
+                envelope: MailEnvelope::new([4, 5, 6].as_slice()).unwrap(),
+            }))
+            .await
+            .unwrap();
+
+        let S2C::Mail(mail) = recipient.recv_de().await.unwrap() else {
+            panic!("Expected S2CMail");
+        };
+        assert_eq!(&*mail.envelope, [4, 5, 6].as_slice());
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn unknown_recipient() {
+        let env = TestEnv::new().await;
+        let duskyelf = env.register("duskyelf").await;
+        let thatmagicalcat = env.register_upstream("thatmagicalcat").await;
+
+        let mut sender = login(&duskyelf, &env.state).await;
+        for id in 1..=3 {
+            sender
+                .send_se(C2S::from(C2SSendMail {
+                    id,
+                    to: thatmagicalcat.clone(),
+                    envelope: MailEnvelope::new([1, 2, 3].as_slice()).unwrap(),
+                }))
+                .await
+                .unwrap();
+
+            let S2C::SendMailResult(reply) = sender.recv_de().await.unwrap() else {
+                panic!("Expected S2CSendMailReply");
+            };
+            assert_eq!(reply.result, S2CSendMailResult::UnknownUser);
+        }
+
+        // None of them fetched upstream, an on-demand refresh is still allowed
+        assert!(env.state.mirror.refresh().await);
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn presence() {
+        let env = TestEnv::new().await;
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        let mut watcher = login(&thatmagicalcat, &env.state).await;
+        watcher
+            .send_se(C2S::from(C2SSubscribePresence {
+                usernames: legos::BoundedVec::new([duskyelf.clone()]).unwrap(),
+            }))
+            .await
+            .unwrap();
+
+        let mut expect_presence = async |online| {
+            let S2C::Presence(presence) = watcher.recv_de().await.unwrap() else {
+                panic!("Expected S2CPresence");
+            };
+            assert_eq!(presence.username, duskyelf);
+            assert_eq!(presence.online, online);
+        };
+        expect_presence(false).await;
+
+        let mut watched = login(&duskyelf, &env.state).await;
+        expect_presence(true).await;
+
+        watched
+            .send_se(C2S::from(C2SSetPresence { hidden: true }))
+            .await
+            .unwrap();
+        expect_presence(false).await;
+
+        watched
+            .send_se(C2S::from(C2SSetPresence { hidden: false }))
+            .await
+            .unwrap();
+        expect_presence(true).await;
+
+        drop(watched);
+        expect_presence(false).await;
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn multiple_devices() {
+        let env = TestEnv::new().await;
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        let mut caller = login(&duskyelf, &env.state).await;
+        let mut laptop = login_device(&thatmagicalcat, 1, &env.state).await;
+        let mut desktop = login_device(&thatmagicalcat, 2, &env.state).await;
+
+        caller
+            .send_se(C2S::from(ConnectToUser {
+                id: 7,
+                username: thatmagicalcat.clone(),
+            }))
+            .await
+            .unwrap();
+
+        // Every device is asked
+        let mut ids = Vec::new();
+        for device in [&mut laptop, &mut desktop] {
+            let S2C::ConnectToUser(ConnectToUser { id, username }) =
+                device.recv_de().await.unwrap()
+            else {
+                panic!("Expected ConnectToUser");
+            };
+            assert_eq!(username, duskyelf);
+            ids.push(id);
+        }
+
+        // The desktop answers first and wins
+        desktop
+            .send_se(C2S::from(C2SConnectToUserReply {
+                id: ids[1],
+                result: C2SConnectToUserResult::Accept,
+            }))
+            .await
+            .unwrap();
+
+        let S2C::ConnectToUserResult(reply) = caller.recv_de().await.unwrap() else {
+            panic!("Expected S2CConnectToUserReply");
+        };
+        assert_eq!(reply.result, S2CConnectToUserResult::Accept);
+
+        let S2C::ConnectHandledElsewhere(S2CConnectHandledElsewhere { username }) =
+            laptop.recv_de().await.unwrap()
+        else {
+            panic!("Expected S2CConnectHandledElsewhere");
+        };
+        assert_eq!(username, duskyelf);
+
+        // Signals only reach the device that accepted
+        caller
+            .send_se(C2S::from(C2SSignal {
+                to: thatmagicalcat.clone(),
+                signal: Signal::Offer(legos::BoundedBytes::new(b"v=0".as_slice()).unwrap()),
+            }))
+            .await
+            .unwrap();
+
+        let S2C::Signal(S2CSignal { from, .. }) = desktop.recv_de().await.unwrap() else {
+            panic!("Expected S2CSignal");
+        };
+        assert_eq!(from, duskyelf);
+        assert!(laptop.rx.try_recv().is_err());
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn resume() {
+        let env = TestEnv::new().await;
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        let mut caller = login(&duskyelf, &env.state).await;
+        let slot = Slot::open(device(&thatmagicalcat, 1), false, &env.state.user_channels).await;
+        let (callee, handle) = serve(slot, &env.state);
+
+        caller
+            .send_se(C2S::from(ConnectToUser {
+                id: 7,
+                username: thatmagicalcat.clone(),
+            }))
+            .await
+            .unwrap();
+
+        // The callee's socket drops before it sees the request
+        drop(callee);
+        let slot = handle.await.unwrap();
+        assert!(env.state.user_channels.is_online(&thatmagicalcat).await);
+
+        // The request is pushed again after resuming, and can still be answered
+        let (mut callee, _handle) = serve(slot, &env.state);
+        let S2C::ConnectToUser(ConnectToUser { id, username }) = callee.recv_de().await.unwrap()
+        else {
+            panic!("Expected ConnectToUser");
+        };
+        assert_eq!(username, duskyelf);
+
+        callee
+            .send_se(C2S::from(C2SConnectToUserReply {
+                id,
+                result: C2SConnectToUserResult::Accept,
+            }))
+            .await
+            .unwrap();
+
+        let S2C::ConnectToUserResult(reply) = caller.recv_de().await.unwrap() else {
+            panic!("Expected S2CConnectToUserReply");
+        };
+        assert_eq!(reply.id, 7);
+        assert_eq!(reply.result, S2CConnectToUserResult::Accept);
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn takeover() {
+        let env = TestEnv::new().await;
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+        let addr = device(&thatmagicalcat, 1);
+        let resumptions = &env.state.resumptions;
+
+        let mut caller = login(&duskyelf, &env.state).await;
+        let slot = Slot::open(addr.clone(), false, &env.state.user_channels).await;
+        resumptions.go_live([7; 32], &slot).await;
+        let (_old_socket, handle) = serve(slot, &env.state);
+
+        caller
+            .send_se(C2S::from(ConnectToUser {
+                id: 7,
+                username: thatmagicalcat.clone(),
+            }))
+            .await
+            .unwrap();
+
+        // A responsive connection keeps its slot
+        let long = Duration::from_secs(60);
+        assert!(!resumptions.take_over(&addr, None, long).await);
+
+        // The device reconnecting takes it over, before the old socket is noticed dead
+        let taker = tokio::spawn({
+            let resumptions = resumptions.clone();
+            let addr = addr.clone();
+            async move {
+                resumptions
+                    .take_over(&addr, Some(&[7; 32]), Duration::ZERO)
+                    .await
+            }
+        });
+        let slot = handle.await.unwrap();
+        resumptions.park([7; 32], slot).await;
+        assert!(taker.await.unwrap());
+
+        // With the pending request
+        let slot = resumptions.take(&addr, &[7; 32]).await.unwrap();
+        let (mut callee, _handle) = serve(slot, &env.state);
+        let S2C::ConnectToUser(ConnectToUser { username, .. }) = callee.recv_de().await.unwrap()
+        else {
+            panic!("Expected ConnectToUser");
+        };
+        assert_eq!(username, duskyelf);
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn reaping() {
+        let mut env = TestEnv::new().await;
+        env.state.heartbeat = Heartbeat {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(50),
+        };
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+
+        let mut client = login(&duskyelf, &env.state).await;
+
+        // Talking keeps the connection alive
+        for id in 0..5 {
+            client
+                .send_se(C2S::from(C2SCancelConnect { id }))
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(env.state.user_channels.is_online(&duskyelf).await);
+
+        // Silence gets it reaped, and the user goes offline
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(
+            client.recv_de::<S2C>().await,
+            Err(ServieError::SocketClosed)
+        ));
+        while env.state.user_channels.is_online(&duskyelf).await {
+            tokio::task::yield_now().await;
+        }
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn mailbox_full() {
+        let mut env = TestEnv::new().await;
+        env.state.user_channels = UserChannels::with_config(QueueConfig {
+            capacity: 1,
+            overflow: Overflow::RejectNewest,
+        });
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let ferris = ShortIdStr::new("ferris").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        // Online, but not reading its channel
+        let _callee = Slot::open(device(&thatmagicalcat, 1), false, &env.state.user_channels).await;
+
+        let mut callers = Vec::new();
+        let mut results = Vec::new();
+        for caller in [&duskyelf, &ferris] {
+            callers.push(login(caller, &env.state).await);
+            let caller = callers.last_mut().unwrap();
+            caller
+                .send_se(C2S::from(ConnectToUser {
+                    id: 7,
+                    username: thatmagicalcat.clone(),
+                }))
+                .await
+                .unwrap();
+
+            // The first request fills the channel and waits for an answer
+            let reply =
+                tokio::time::timeout(Duration::from_millis(50), caller.recv_de::<S2C>()).await;
+            results.push(reply.ok().map(|reply| match reply.unwrap() {
+                S2C::ConnectToUserResult(reply) => reply.result,
+                _ => panic!("Expected S2CConnectToUserReply"),
+            }));
+        }
+
+        assert_eq!(results, [None, Some(S2CConnectToUserResult::MailboxFull)]);
+        assert_eq!(env.state.user_channels.overflows().await, 1);
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn blocking() {
+        let env = TestEnv::new().await;
+        let duskyelf = env.register("duskyelf").await;
+        let thatmagicalcat = env.register("thatmagicalcat").await;
+
+        let mut caller = login(&duskyelf, &env.state).await;
+        let mut callee = login(&thatmagicalcat, &env.state).await;
+
+        for (id, blocked) in [(1, true), (2, false)] {
+            callee
+                .send_se(C2S::from(C2SSetBlocked {
+                    id,
+                    username: duskyelf.clone(),
+                    blocked,
+                }))
+                .await
+                .unwrap();
+            let S2C::SetBlockedResult(reply) = callee.recv_de().await.unwrap() else {
+                panic!("Expected S2CSetBlockedReply");
+            };
+            assert_eq!(reply.id, id);
+            assert_eq!(reply.result, S2CSetBlockedResult::Done);
+
+            callee
+                .send_se(C2S::from(C2SGetBlockList { id }))
+                .await
+                .unwrap();
+            let S2C::BlockList(list) = callee.recv_de().await.unwrap() else {
+                panic!("Expected S2CBlockList");
+            };
+            assert_eq!(list.usernames.is_empty(), !blocked);
+
+            caller
+                .send_se(C2S::from(ConnectToUser {
+                    id,
+                    username: thatmagicalcat.clone(),
+                }))
+                .await
+                .unwrap();
+
+            if blocked {
+                // Looks like the callee is offline, and they never hear of it
+                let S2C::ConnectToUserResult(reply) = caller.recv_de().await.unwrap() else {
+                    panic!("Expected S2CConnectToUserReply");
+                };
+                assert_eq!(reply.result, S2CConnectToUserResult::Offline);
+                assert!(callee.rx.try_recv().is_err());
+            } else {
+                let S2C::ConnectToUser(ConnectToUser { username, .. }) =
+                    callee.recv_de().await.unwrap()
+                else {
+                    panic!("Expected ConnectToUser");
+                };
+                assert_eq!(username, duskyelf);
+            }
+        }
+
+        // Mail seems to go through, but is dropped
+        callee
+            .send_se(C2S::from(C2SSetBlocked {
+                id: 3,
+                username: duskyelf.clone(),
+                blocked: true,
+            }))
+            .await
+            .unwrap();
+        let S2C::SetBlockedResult(_) = callee.recv_de().await.unwrap() else {
+            panic!("Expected S2CSetBlockedReply");
+        };
+
+        caller
+            .send_se(C2S::from(C2SSendMail {
+                id: 3,
+                to: thatmagicalcat.clone(),
+                envelope: MailEnvelope::new([1, 2, 3].as_slice()).unwrap(),
+            }))
+            .await
+            .unwrap();
+        let S2C::SendMailResult(reply) = caller.recv_de().await.unwrap() else {
+            panic!("Expected S2CSendMailReply");
+        };
+        assert_eq!(reply.result, S2CSendMailResult::Stored);
+
+        drop(callee);
+        let mut callee = login(&thatmagicalcat, &env.state).await;
+        callee
+            .send_se(C2S::from(C2SGetBlockList { id: 4 }))
+            .await
+            .unwrap();
+        assert!(matches!(callee.recv_de().await.unwrap(), S2C::BlockList(_)));
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn blocked_presence() {
+        let env = TestEnv::new().await;
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        let mut blocker = login(&duskyelf, &env.state).await;
+        let set_blocked = async |blocker: &mut TestSocket, id, blocked| {
+            blocker
+                .send_se(C2S::from(C2SSetBlocked {
+                    id,
+                    username: thatmagicalcat.clone(),
+                    blocked,
+                }))
+                .await
+                .unwrap();
+            let S2C::SetBlockedResult(_) = blocker.recv_de().await.unwrap() else {
+                panic!("Expected S2CSetBlockedReply");
+            };
+        };
+        set_blocked(&mut blocker, 1, true).await;
+
+        let mut watcher = login(&thatmagicalcat, &env.state).await;
+        watcher
+            .send_se(C2S::from(C2SSubscribePresence {
+                usernames: legos::BoundedVec::new([duskyelf.clone()]).unwrap(),
+            }))
+            .await
+            .unwrap();
+        let mut expect_presence = async |online| {
+            let S2C::Presence(presence) = watcher.recv_de().await.unwrap() else {
+                panic!("Expected S2CPresence");
+            };
+            assert_eq!(presence.username, duskyelf);
+            assert_eq!(presence.online, online);
+        };
+
+        // Blocked users see the blocker offline, even as they come online
+        expect_presence(false).await;
+        for hidden in [true, false] {
+            blocker
+                .send_se(C2S::from(C2SSetPresence { hidden }))
+                .await
+                .unwrap();
+            expect_presence(false).await;
+        }
+
+        // And online again once unblocked
+        set_blocked(&mut blocker, 2, false).await;
+        expect_presence(true).await;
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn contact_request_privacy() {
+        let env = TestEnv::new().await;
+        let duskyelf = env.register("duskyelf").await;
+        let hidden = env.register("thatmagicalcat").await;
+        let offline = env.register("ferris").await;
+        let blocker = env.register("blocker").await;
+        env.state
+            .blocklist
+            .set_blocked(&blocker, duskyelf.clone(), true)
+            .await
+            .unwrap();
+
+        let slot = Slot::open(device(&hidden, 0), true, &env.state.user_channels).await;
+        let (mut hidden_socket, _) = serve(slot, &env.state);
+        let mut sender = login(&duskyelf, &env.state).await;
+
+        // The same answer whether the user is hidden, offline or blocks the sender
+        for (id, to) in [(1, &hidden), (2, &offline), (3, &blocker)] {
+            sender
+                .send_se(C2S::from(C2SContactRequest {
+                    id,
+                    username: to.clone(),
+                }))
+                .await
+                .unwrap();
+            let S2C::ContactRequestResult(reply) = sender.recv_de().await.unwrap() else {
+                panic!("Expected S2CContactRequestReply");
+            };
+            assert_eq!(reply.id, id);
+            assert_eq!(reply.result, S2CContactRequestResult::Sent);
+        }
+
+        let S2C::ContactRequest(S2CContactRequest { username }) =
+            hidden_socket.recv_de().await.unwrap()
+        else {
+            panic!("Expected S2CContactRequest");
+        };
+        assert_eq!(username, duskyelf);
+
+        // Stored until the user logs in
+        let mut offline = login(&offline, &env.state).await;
+        let S2C::ContactRequest(S2CContactRequest { username }) = offline.recv_de().await.unwrap()
+        else {
+            panic!("Expected S2CContactRequest");
+        };
+        assert_eq!(username, duskyelf);
+
+        // Dropped for a blocker, the reply to this is the first thing they get
+        let mut blocker = login(&blocker, &env.state).await;
+        blocker
+            .send_se(C2S::from(C2SGetContacts { id: 4 }))
+            .await
+            .unwrap();
+        let S2C::Contacts(_) = blocker.recv_de().await.unwrap() else {
+            panic!("Expected S2CContacts");
+        };
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn contacts_only() {
+        let env = TestEnv::new().await;
+        let duskyelf = env.register("duskyelf").await;
+        let thatmagicalcat = env.register("thatmagicalcat").await;
+
+        let mut caller = login(&duskyelf, &env.state).await;
+        let mut callee = login(&thatmagicalcat, &env.state).await;
+
+        callee
+            .send_se(C2S::from(C2SSetContactsOnly { enabled: true }))
+            .await
+            .unwrap();
+        callee
+            .send_se(C2S::from(C2SGetContacts { id: 0 }))
+            .await
+            .unwrap();
+        let S2C::Contacts(S2CContacts {
+            contacts_only,
+            usernames,
+            ..
+        }) = callee.recv_de().await.unwrap()
+        else {
+            panic!("Expected S2CContacts");
+        };
+        assert!(contacts_only);
+        assert!(usernames.is_empty());
+
+        // Strangers see the callee as offline
+        caller
+            .send_se(C2S::from(ConnectToUser {
+                id: 1,
+                username: thatmagicalcat.clone(),
+            }))
+            .await
+            .unwrap();
+        let S2C::ConnectToUserResult(reply) = caller.recv_de().await.unwrap() else {
+            panic!("Expected S2CConnectToUserReply");
+        };
+        assert_eq!(reply.result, S2CConnectToUserResult::Offline);
+
+        // But can ask to become a contact
+        caller
+            .send_se(C2S::from(C2SContactRequest {
+                id: 2,
+                username: thatmagicalcat.clone(),
+            }))
+            .await
+            .unwrap();
+        let S2C::ContactRequestResult(reply) = caller.recv_de().await.unwrap() else {
+            panic!("Expected S2CContactRequestReply");
+        };
+        assert_eq!(reply.result, S2CContactRequestResult::Sent);
+
+        let S2C::ContactRequest(S2CContactRequest { username }) = callee.recv_de().await.unwrap()
+        else {
+            panic!("Expected S2CContactRequest");
+        };
+        assert_eq!(username, duskyelf);
+
+        callee
+            .send_se(C2S::from(C2SSetContact {
+                id: 3,
+                username,
+                contact: true,
+            }))
+            .await
+            .unwrap();
+        let S2C::SetContactResult(reply) = callee.recv_de().await.unwrap() else {
+            panic!("Expected S2CSetContactReply");
+        };
+        assert_eq!(reply.result, S2CSetContactResult::Done);
+
+        callee
+            .send_se(C2S::from(C2SGetContacts { id: 4 }))
+            .await
+            .unwrap();
+        let S2C::Contacts(S2CContacts {
+            contacts_only,
+            usernames,
+            ..
+        }) = callee.recv_de().await.unwrap()
+        else {
+            panic!("Expected S2CContacts");
+        };
+        assert!(contacts_only);
+        assert_eq!(&*usernames, std::slice::from_ref(&duskyelf));
+
+        // Contacts get through
+        caller
+            .send_se(C2S::from(ConnectToUser {
+                id: 5,
+                username: thatmagicalcat.clone(),
+            }))
+            .await
+            .unwrap();
+        let S2C::ConnectToUser(ConnectToUser { username, .. }) = callee.recv_de().await.unwrap()
+        else {
+            panic!("Expected ConnectToUser");
+        };
+        assert_eq!(username, duskyelf);
+
+        env.cleanup();
+    }
+
+    #[tokio::test]
+    async fn contacts_only_presence() {
+        let env = TestEnv::new().await;
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        let mut watcher = login(&duskyelf, &env.state).await;
+        let mut watched = login(&thatmagicalcat, &env.state).await;
+        watcher
+            .send_se(C2S::from(C2SSubscribePresence {
+                usernames: legos::BoundedVec::new([thatmagicalcat.clone()]).unwrap(),
+            }))
+            .await
+            .unwrap();
+        let mut expect_presence = async |online| {
+            let S2C::Presence(presence) = watcher.recv_de().await.unwrap() else {
+                panic!("Expected S2CPresence");
+            };
+            assert_eq!(presence.username, thatmagicalcat);
+            assert_eq!(presence.online, online);
+        };
+        expect_presence(true).await;
+
+        // Only contacts see a user in contacts-only mode online
+        watched
+            .send_se(C2S::from(C2SSetContactsOnly { enabled: true }))
+            .await
+            .unwrap();
+        expect_presence(false).await;
+
+        for (id, contact) in [(1, true), (2, false)] {
+            watched
+                .send_se(C2S::from(C2SSetContact {
+                    id,
+                    username: duskyelf.clone(),
+                    contact,
+                }))
+                .await
+                .unwrap();
+            expect_presence(contact).await;
+        }
+
+        watched
+            .send_se(C2S::from(C2SSetContactsOnly { enabled: false }))
+            .await
+            .unwrap();
+        expect_presence(true).await;
+
+        env.cleanup();
+    }
+}
@@ -0,0 +1,100 @@
+use crate::{
+    AppState, Blocklist, Contacts, Heartbeat, Mailbox, Mirror, RelayQuota, Resumptions,
+    UserChannels,
+};
+
+use registrie::{commit_signed, new_record, SigningKey, DEFAULT_BRANCH};
+use schemou::legos::ShortIdStr;
+
+use std::{fs, sync::Arc};
+
+use fips204::ml_dsa_87;
+use git2::Repository;
+use tokio::sync::Mutex;
+
+pub fn init_upstream(path: &str, key: &SigningKey) -> Arc<Mutex<Repository>> {
+    let repo = Repository::init_bare(path).unwrap();
+    {
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let commit = commit_signed(&repo, key, "Initial Commit", &tree, &[]).unwrap();
+        repo.branch(DEFAULT_BRANCH, &repo.find_commit(commit).unwrap(), false)
+            .unwrap();
+    }
+    Arc::new(Mutex::new(repo))
+}
+
+pub fn file_url(path: &str) -> String {
+    format!(
+        "file://{}",
+        fs::canonicalize(path).unwrap().to_str().unwrap()
+    )
+}
+
+/// Servie state backed by a local upstream registrie, a mirror, a mailbox, a block list
+/// and contacts in temp dirs
+pub struct TestEnv {
+    pub state: AppState,
+    upstream: Arc<Mutex<Repository>>,
+    key: Arc<SigningKey>,
+    paths: [String; 5],
+}
+
+impl TestEnv {
+    pub async fn new() -> Self {
+        let paths = [(); 5].map(|_| rand::random::<u64>().to_string());
+        let [upstream_path, mirror_path, mailbox_path, blocklist_path, contacts_path] = &paths;
+        let (verifying_key, key) = ml_dsa_87::try_keygen().unwrap();
+        let key = Arc::new(key);
+
+        let upstream = init_upstream(upstream_path, &key);
+        let mirror = Mirror::open(mirror_path, &file_url(upstream_path), verifying_key)
+            .await
+            .unwrap();
+
+        Self {
+            state: AppState {
+                mirror,
+                user_channels: UserChannels::new(),
+                mailbox: Mailbox::open(mailbox_path).unwrap(),
+                blocklist: Blocklist::open(blocklist_path).unwrap(),
+                contacts: Contacts::open(contacts_path).unwrap(),
+                resumptions: Resumptions::new(),
+                relay: RelayQuota::new(),
+                heartbeat: Heartbeat::default(),
+            },
+            upstream,
+            key,
+            paths,
+        }
+    }
+
+    /// Registers `username` upstream and pulls it into the mirror
+    pub async fn register(&self, username: &str) -> ShortIdStr {
+        let username = self.register_upstream(username).await;
+        self.state.mirror.fetch_db().await.unwrap();
+        username
+    }
+
+    /// Registers `username` upstream only, the mirror doesn't know them until it fetches
+    pub async fn register_upstream(&self, username: &str) -> ShortIdStr {
+        let username = ShortIdStr::new(username).unwrap();
+        new_record(
+            self.upstream.clone(),
+            self.key.clone(),
+            username.clone(),
+            [1, 2, 3].into(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        username
+    }
+
+    pub fn cleanup(self) {
+        for path in self.paths {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+}
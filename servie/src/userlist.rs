@@ -0,0 +1,184 @@
+use crate::user_path;
+
+use schemou::legos::ShortIdStr;
+
+use std::{collections::HashSet, fs, io, path::Path, sync::Arc};
+
+use tokio::{sync::Mutex, task::spawn_blocking};
+
+/// A list of users kept for each user, like who they block or who their contacts are
+///
+/// One file per owning user with a listed username per line
+#[derive(Clone)]
+pub struct UserList {
+    root: Arc<Path>,
+    /// Most users a list can hold
+    max: usize,
+    /// Serializes the read-modify-write of `set`
+    lock: Arc<Mutex<()>>,
+}
+
+impl UserList {
+    // This function blocks on io operations
+    // That's fine as it's called once at the very start
+    pub fn open(path: impl AsRef<Path>, max: usize) -> io::Result<Self> {
+        fs::create_dir_all(&path)?;
+
+        Ok(Self {
+            root: path.as_ref().into(),
+            max,
+            lock: Default::default(),
+        })
+    }
+
+    /// Users on `user`'s list, sorted
+    pub async fn members(&self, user: &ShortIdStr) -> io::Result<Vec<ShortIdStr>> {
+        let path = user_path(&self.root, user);
+        let members = spawn_blocking(move || read(&path)).await.unwrap()?;
+        Ok(sorted(&members))
+    }
+
+    /// Whether `other` is on `user`'s list
+    pub async fn contains(&self, user: &ShortIdStr, other: &ShortIdStr) -> io::Result<bool> {
+        let path = user_path(&self.root, user);
+        let members = spawn_blocking(move || read(&path)).await.unwrap()?;
+        Ok(members.contains(other))
+    }
+
+    /// Adds `other` to `user`'s list, or removes them if `member` is `false`,
+    /// returns `false` if adding would take the list over its maximum
+    pub async fn set(
+        &self,
+        user: &ShortIdStr,
+        other: ShortIdStr,
+        member: bool,
+    ) -> io::Result<bool> {
+        let _guard = self.lock.lock().await;
+        let path = user_path(&self.root, user);
+        let max = self.max;
+
+        spawn_blocking(move || {
+            let mut list = read(&path)?;
+            let changed = match member {
+                true if list.len() >= max && !list.contains(&other) => return Ok(false),
+                true => list.insert(other),
+                false => list.remove(&other),
+            };
+
+            if changed {
+                write(&path, &list)?;
+            }
+            Ok(true)
+        })
+        .await
+        .unwrap()
+    }
+}
+
+/// Empty if the file doesn't exist, skipping lines that aren't valid usernames
+fn read(path: &Path) -> io::Result<HashSet<ShortIdStr>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(err) => return Err(err),
+    };
+
+    Ok(contents
+        .lines()
+        .filter_map(|line| match ShortIdStr::new(line) {
+            Ok(username) => Some(username),
+            Err(err) => {
+                tracing::warn!(path = %path.display(), "skipping corrupted user list entry: {err}");
+                None
+            }
+        })
+        .collect())
+}
+
+/// Replaces the file through a rename, so a crash can't leave it half written
+fn write(path: &Path, list: &HashSet<ShortIdStr>) -> io::Result<()> {
+    if list.is_empty() {
+        return match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        };
+    }
+
+    let contents: String = sorted(list)
+        .iter()
+        .map(|user| format!("{}\n", user.as_str()))
+        .collect();
+    // '~' isn't allowed in usernames, so this can't be another user's file
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push("~");
+    fs::write(&tmp, contents)?;
+    fs::rename(tmp, path)
+}
+
+fn sorted(list: &HashSet<ShortIdStr>) -> Vec<ShortIdStr> {
+    let mut list: Vec<_> = list.iter().cloned().collect();
+    list.sort_unstable_by(|a, b| a.as_str().cmp(b.as_str()));
+    list
+}
+
+#[cfg(test)]
+mod userlist_tests {
+    use super::UserList;
+
+    use schemou::legos::ShortIdStr;
+
+    use std::fs;
+
+    #[tokio::test]
+    async fn add_and_remove() {
+        let path = rand::random::<u64>().to_string();
+        let list = UserList::open(&path, 8).unwrap();
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+        let thatmagicalcat = ShortIdStr::new("thatmagicalcat").unwrap();
+
+        assert!(!list.contains(&duskyelf, &thatmagicalcat).await.unwrap());
+
+        assert!(list
+            .set(&duskyelf, thatmagicalcat.clone(), true)
+            .await
+            .unwrap());
+        assert!(list.contains(&duskyelf, &thatmagicalcat).await.unwrap());
+
+        // Lists are one way, and survive reopening
+        let list = UserList::open(&path, 8).unwrap();
+        assert!(!list.contains(&thatmagicalcat, &duskyelf).await.unwrap());
+        assert_eq!(
+            list.members(&duskyelf).await.unwrap(),
+            std::slice::from_ref(&thatmagicalcat)
+        );
+
+        assert!(list
+            .set(&duskyelf, thatmagicalcat.clone(), false)
+            .await
+            .unwrap());
+        assert!(list.members(&duskyelf).await.unwrap().is_empty());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn limit() {
+        let path = rand::random::<u64>().to_string();
+        let list = UserList::open(&path, 8).unwrap();
+        let duskyelf = ShortIdStr::new("duskyelf").unwrap();
+
+        for i in 0..8 {
+            let member = ShortIdStr::new(format!("user{i}")).unwrap();
+            assert!(list.set(&duskyelf, member, true).await.unwrap());
+        }
+
+        let one_more = ShortIdStr::new("thatmagicalcat").unwrap();
+        assert!(!list.set(&duskyelf, one_more, true).await.unwrap());
+
+        // Adding someone already listed is still fine
+        let member = ShortIdStr::new("user0").unwrap();
+        assert!(list.set(&duskyelf, member, true).await.unwrap());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+}